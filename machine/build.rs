@@ -4,18 +4,123 @@ fn main() {
     let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
     let ld = &out.join("rustsbi-machine.ld");
 
-    std::fs::write(ld, LINKER_SCRIPT).unwrap();
+    let mut linker_script = LINKER_SCRIPT.to_vec();
+    // an embedded payload is only used without dynamic information or a jump address
+    if env::var_os("CARGO_FEATURE_PAYLOAD").is_some()
+        && env::var_os("CARGO_FEATURE_DYNAMIC").is_none()
+        && env::var_os("CARGO_FEATURE_JUMP").is_none()
+    {
+        linker_script.extend_from_slice(payload_assert().as_bytes());
+    }
+    std::fs::write(ld, linker_script).unwrap();
 
     println!("cargo:rustc-link-arg=-T{}", ld.display());
     println!("cargo:rustc-link-search={}", out.display());
+
+    if env::var_os("CARGO_FEATURE_JUMP").is_some() {
+        std::fs::write(out.join("jump.rs"), jump_source()).unwrap();
+    }
+    if env::var_os("CARGO_FEATURE_PAYLOAD").is_some() {
+        check_payload();
+    }
+}
+
+const DEFAULT_JUMP_ADDRESS: usize = 0x80200000;
+/// Link address of `rustsbi-supervisor`, the default payload.
+const DEFAULT_PAYLOAD_ADDRESS: usize = 0x80200000;
+
+fn jump_source() -> String {
+    println!("cargo:rerun-if-env-changed=RUSTSBI_JUMP_ADDRESS");
+    println!("cargo:rerun-if-env-changed=RUSTSBI_JUMP_MODE");
+    let address = match env::var("RUSTSBI_JUMP_ADDRESS") {
+        Ok(s) => {
+            parse_address(&s).unwrap_or_else(|| panic!("invalid RUSTSBI_JUMP_ADDRESS '{}'", s))
+        }
+        Err(_) => DEFAULT_JUMP_ADDRESS,
+    };
+    let mode = match env::var("RUSTSBI_JUMP_MODE") {
+        Ok(s) => parse_mode(&s).unwrap_or_else(|| {
+            panic!(
+                "invalid RUSTSBI_JUMP_MODE '{}', expected one of 'U', 'S' or 'M'",
+                s
+            )
+        }),
+        Err(_) => "Supervisor",
+    };
+    format!(
+        "/// Address of the next boot-loading stage.
+pub const JUMP_ADDRESS: usize = 0x{:x};
+/// RISC-V privilege mode of the next boot-loading stage.
+pub const JUMP_MODE: riscv::register::mstatus::MPP = riscv::register::mstatus::MPP::{};
+",
+        address, mode
+    )
+}
+
+fn check_payload() {
+    println!("cargo:rerun-if-env-changed=RUSTSBI_PAYLOAD");
+    let Some(path) = env::var_os("RUSTSBI_PAYLOAD").map(PathBuf::from) else {
+        panic!("feature `payload` requires RUSTSBI_PAYLOAD to be set to a binary image path");
+    };
+    if !path.is_absolute() {
+        panic!(
+            "RUSTSBI_PAYLOAD '{}' is not an absolute path",
+            path.display()
+        );
+    }
+    let image =
+        std::fs::read(&path).unwrap_or_else(|e| panic!("read payload '{}': {}", path.display(), e));
+    if image.starts_with(b"\x7fELF") {
+        panic!(
+            "payload '{}' is an ELF file; convert it into binary using objcopy first",
+            path.display()
+        );
+    }
+}
+
+/// Link-time check that the payload starts at the address its image was linked for.
+fn payload_assert() -> String {
+    println!("cargo:rerun-if-env-changed=RUSTSBI_PAYLOAD_ADDRESS");
+    let address = match env::var("RUSTSBI_PAYLOAD_ADDRESS") {
+        Ok(s) => {
+            parse_address(&s).unwrap_or_else(|| panic!("invalid RUSTSBI_PAYLOAD_ADDRESS '{}'", s))
+        }
+        Err(_) => DEFAULT_PAYLOAD_ADDRESS,
+    };
+    format!(
+        "
+ASSERT(ADDR(.payload) == 0x{:x}, \"payload is not placed at RUSTSBI_PAYLOAD_ADDRESS, where its image is linked\")
+",
+        address
+    )
+}
+
+fn parse_address(s: &str) -> Option<usize> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_mode(s: &str) -> Option<&'static str> {
+    match s.trim() {
+        "U" | "u" | "0" => Some("User"),
+        "S" | "s" | "1" => Some("Supervisor"),
+        "M" | "m" | "3" => Some("Machine"),
+        _ => None,
+    }
 }
 
 const LINKER_SCRIPT: &[u8] = b"OUTPUT_ARCH(riscv)
 ENTRY(_start) 
 SECTIONS {
     . = 0x80000000;
+    sfirmware = .;
     .text : ALIGN(8) { 
         *(.text.entry)
+        . = ALIGN(4);
+        *(.text.trap_entry)
         *(.text .text.*)
     }
     .rodata : ALIGN(8) { 
@@ -25,6 +130,9 @@ SECTIONS {
         . = ALIGN(8);  
         erodata = .;
     } 
+    .data.boot : ALIGN(8) {
+        *(.data.boot)
+    }
     .data : ALIGN(8) { 
         sdata = .;
         *(.data .data.*)
@@ -40,6 +148,13 @@ SECTIONS {
         *(.sbss .sbss.*)
         ebss = .;
     } 
+    efirmware = .;
+    .fdt (NOLOAD) : ALIGN(8) {
+        *(.fdt)
+    }
+    .payload : ALIGN(0x200000) {
+        *(.payload)
+    }
     /DISCARD/ : {
         *(.eh_frame)
     }
@@ -0,0 +1,95 @@
+//! Machine-mode software interrupts, tagged with reasons so that firmware services
+//! and supervisor IPIs can share the same CLINT `msip` register.
+
+use crate::NUM_HART_MAX;
+use aclint::SifiveClint;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+static CLINT: Mutex<MachineClint> = Mutex::new(MachineClint::Absent);
+
+/// Supervisor software interrupt requested by SBI IPI `send_ipi`.
+pub const IPI_SUPERVISOR: usize = 1 << 0;
+/// Remote fence request posted into the hart's mailbox.
+pub const IPI_FENCE: usize = 1 << 1;
+/// Hart start request from SBI HSM `hart_start`.
+#[cfg(feature = "hsm")]
+pub const IPI_HSM: usize = 1 << 2;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const IPI_REASON_INIT: AtomicUsize = AtomicUsize::new(0);
+static IPI_REASONS: [AtomicUsize; NUM_HART_MAX] = [IPI_REASON_INIT; NUM_HART_MAX];
+
+/// Sends machine software interrupt with `reason` to given hart.
+///
+/// Returns `false` if no CLINT is present or hart ID is out of range.
+pub fn send_ipi(hart_id: usize, reason: usize) -> bool {
+    let Some(reasons) = crate::hart::entry_of(&IPI_REASONS, hart_id) else {
+        return false;
+    };
+    reasons.fetch_or(reason, Ordering::AcqRel);
+    set_msip(hart_id)
+}
+
+/// Clears machine software interrupt of given hart and returns all pending reasons.
+pub fn take_ipi(hart_id: usize) -> usize {
+    clear_msip(hart_id);
+    crate::hart::entry_of(&IPI_REASONS, hart_id)
+        .map_or(0, |reasons| reasons.swap(0, Ordering::AcqRel))
+}
+
+fn set_msip(hart_id: usize) -> bool {
+    let lock = CLINT.lock();
+    match *lock {
+        MachineClint::Absent => false,
+        MachineClint::SifiveClint(clint) => {
+            unsafe { &*clint }.set_msip(hart_id);
+            true
+        }
+    }
+}
+
+fn clear_msip(hart_id: usize) {
+    let lock = CLINT.lock();
+    if let MachineClint::SifiveClint(clint) = *lock {
+        unsafe { &*clint }.clear_msip(hart_id)
+    }
+}
+
+/// Whether harts can be woken from `wfi` by machine software interrupts.
+pub fn is_present() -> bool {
+    !matches!(*CLINT.lock(), MachineClint::Absent)
+}
+
+/// Current `mtime` in nanoseconds, if CLINT and timebase frequency are known.
+pub fn mtime_ns() -> Option<u64> {
+    let frequency = *TIMEBASE_FREQUENCY.get()?;
+    let mtime = match *CLINT.lock() {
+        MachineClint::Absent => return None,
+        MachineClint::SifiveClint(clint) => unsafe { &*clint }.read_mtime(),
+    };
+    Some((mtime as u128 * 1_000_000_000 / frequency as u128) as u64)
+}
+
+/// Frequency of `mtime` in Hz.
+static TIMEBASE_FREQUENCY: spin::Once<u64> = spin::Once::new();
+
+#[cfg(feature = "fdt")]
+pub fn load_timebase_frequency(frequency: u64) {
+    TIMEBASE_FREQUENCY.call_once(|| frequency);
+}
+
+enum MachineClint {
+    Absent,
+    SifiveClint(*const SifiveClint),
+}
+
+unsafe impl Send for MachineClint {}
+unsafe impl Sync for MachineClint {}
+
+#[cfg(feature = "fdt")] // TODO
+pub fn load_clint(clint: &SifiveClint) {
+    let mut lock = CLINT.lock();
+    *lock = MachineClint::SifiveClint(clint);
+    drop(lock);
+}
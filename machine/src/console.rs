@@ -9,12 +9,12 @@ use uart16550::Uart16550;
 #[doc(hidden)]
 pub enum MachineConsole {
     Uart16550(*const Uart16550<u8>),
+    Uart16550U32(*const Uart16550<u32>),
 }
 
-impl fmt::Write for MachineConsole {
-    #[inline]
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        let mut bytes = s.as_bytes();
+impl MachineConsole {
+    /// Writes all `bytes`, waiting until the transmitter accepts them.
+    fn write_bytes(&mut self, mut bytes: &[u8]) {
         match self {
             Self::Uart16550(uart16550) => {
                 while !bytes.is_empty() {
@@ -22,7 +22,31 @@ impl fmt::Write for MachineConsole {
                     bytes = &bytes[count..];
                 }
             }
+            Self::Uart16550U32(uart16550) => {
+                while !bytes.is_empty() {
+                    let count = unsafe { &**uart16550 }.write(bytes);
+                    bytes = &bytes[count..];
+                }
+            }
         }
+    }
+
+    /// Reads a received byte, if any.
+    #[cfg(feature = "legacy")]
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut buf = [0u8];
+        let count = match self {
+            Self::Uart16550(uart16550) => unsafe { &**uart16550 }.read(&mut buf),
+            Self::Uart16550U32(uart16550) => unsafe { &**uart16550 }.read(&mut buf),
+        };
+        (count != 0).then_some(buf[0])
+    }
+}
+
+impl fmt::Write for MachineConsole {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
         Ok(())
     }
 }
@@ -34,6 +58,18 @@ unsafe impl Sync for MachineConsole {}
 pub static CONSOLE: Mutex<MachineConsole> =
     Mutex::new(MachineConsole::Uart16550(0x10000000 as *const _));
 
+/// Writes a byte to the firmware console.
+#[cfg(feature = "legacy")]
+pub fn putchar(byte: u8) {
+    CONSOLE.lock().write_bytes(&[byte]);
+}
+
+/// Reads a byte from the firmware console without waiting.
+#[cfg(feature = "legacy")]
+pub fn getchar() -> Option<u8> {
+    CONSOLE.lock().read_byte()
+}
+
 pub fn init() {
     log::set_max_level(
         option_env!("RUST_LOG")
@@ -76,3 +112,10 @@ pub fn load_console_uart16550(uart16550: &Uart16550<u8>) {
     *console = MachineConsole::Uart16550(uart16550);
     drop(console);
 }
+
+#[cfg(feature = "fdt")] // TODO
+pub fn load_console_uart16550_u32(uart16550: &Uart16550<u32>) {
+    let mut console = CONSOLE.lock();
+    *console = MachineConsole::Uart16550U32(uart16550);
+    drop(console);
+}
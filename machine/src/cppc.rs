@@ -0,0 +1,97 @@
+//! Collaborative processor performance control through a platform table of CPPC registers.
+
+#[cfg(feature = "simulated-cppc")]
+mod simulated;
+
+use riscv::register::mhartid;
+use rustsbi::SbiRet;
+
+/// Last register defined by ACPI; IDs up to this one are not reserved.
+const LAST_ACPI_REGISTER: u32 = 0x14;
+/// The only register defined by SBI beyond ACPI ones.
+const TRANSITION_LATENCY: u32 = 0x8000_0000;
+
+/// A CPPC register implemented by the platform.
+pub struct CppcRegister {
+    pub reg_id: u32,
+    /// Register width in bits, 32 or 64.
+    pub width: u32,
+    pub access: CppcAccess,
+}
+
+/// How a CPPC register is accessed, with hart ID passed to access functions.
+// only constructed by platform tables, which may all be disabled
+#[cfg_attr(not(feature = "simulated-cppc"), allow(dead_code))]
+pub enum CppcAccess {
+    /// Read-only register of a fixed value.
+    Constant(u64),
+    ReadOnly(fn(usize) -> u64),
+    ReadWrite(fn(usize) -> u64, fn(usize, u64)),
+}
+
+/// CPPC registers of the platform this firmware is built for, if any.
+pub fn platform_registers() -> Option<&'static [CppcRegister]> {
+    match () {
+        #[cfg(feature = "simulated-cppc")]
+        () => Some(simulated::REGISTERS),
+        #[cfg(not(feature = "simulated-cppc"))]
+        () => None,
+    }
+}
+
+/// SBI CPPC extension backed by a table of CPPC registers, accessed on current hart.
+pub struct MachineCppc {
+    pub registers: &'static [CppcRegister],
+}
+
+impl MachineCppc {
+    fn find(&self, reg_id: u32) -> Result<&CppcRegister, SbiRet> {
+        if reg_id > LAST_ACPI_REGISTER && reg_id != TRANSITION_LATENCY {
+            return Err(SbiRet::invalid_param());
+        }
+        self.registers
+            .iter()
+            .find(|register| register.reg_id == reg_id)
+            .ok_or(SbiRet::not_supported())
+    }
+}
+
+impl rustsbi::Cppc for MachineCppc {
+    fn probe(&self, reg_id: u32) -> SbiRet {
+        match self.find(reg_id) {
+            Ok(register) => SbiRet::success(register.width as usize),
+            Err(ans) if ans == SbiRet::not_supported() => SbiRet::success(0),
+            Err(ans) => ans,
+        }
+    }
+
+    fn read(&self, reg_id: u32) -> SbiRet {
+        let value = match self.find(reg_id).map(|register| &register.access) {
+            Ok(CppcAccess::Constant(value)) => *value,
+            Ok(CppcAccess::ReadOnly(read) | CppcAccess::ReadWrite(read, _)) => {
+                read(mhartid::read())
+            }
+            Err(ans) => return ans,
+        };
+        SbiRet::success(value as usize)
+    }
+
+    /// Upper 32 bits are only returned separately on RV32; this firmware runs on RV64.
+    fn read_hi(&self, reg_id: u32) -> SbiRet {
+        match self.find(reg_id) {
+            Ok(_) => SbiRet::success(0),
+            Err(ans) => ans,
+        }
+    }
+
+    fn write(&self, reg_id: u32, val: u64) -> SbiRet {
+        match self.find(reg_id).map(|register| &register.access) {
+            Ok(CppcAccess::ReadWrite(_, write)) => {
+                write(mhartid::read(), val);
+                SbiRet::success(0)
+            }
+            Ok(_) => SbiRet::denied(),
+            Err(ans) => ans,
+        }
+    }
+}
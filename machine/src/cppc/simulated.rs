@@ -0,0 +1,148 @@
+//! Simulated CPPC registers for platforms without performance control, e.g. QEMU virt.
+//!
+//! Performance levels take effect only on the delivered performance counter, which
+//! advances from `mcycle` at the rate of desired performance relative to nominal.
+
+use super::{CppcAccess, CppcRegister, TRANSITION_LATENCY};
+use crate::NUM_HART_MAX;
+use riscv::register::mcycle;
+use spin::Mutex;
+
+const HIGHEST_PERFORMANCE: u32 = 0x00;
+const NOMINAL_PERFORMANCE: u32 = 0x01;
+const LOWEST_NONLINEAR_PERFORMANCE: u32 = 0x02;
+const LOWEST_PERFORMANCE: u32 = 0x03;
+const GUARANTEED_PERFORMANCE: u32 = 0x04;
+const DESIRED_PERFORMANCE: u32 = 0x05;
+const MINIMUM_PERFORMANCE: u32 = 0x06;
+const MAXIMUM_PERFORMANCE: u32 = 0x07;
+const REFERENCE_PERFORMANCE_COUNTER: u32 = 0x0b;
+const DELIVERED_PERFORMANCE_COUNTER: u32 = 0x0c;
+const CPPC_ENABLE: u32 = 0x0e;
+const LOWEST_FREQUENCY: u32 = 0x13;
+const NOMINAL_FREQUENCY: u32 = 0x14;
+
+const HIGHEST: u64 = 120;
+const NOMINAL: u64 = 100;
+const LOWEST_NONLINEAR: u64 = 50;
+const LOWEST: u64 = 20;
+/// Frequencies in MHz at lowest and nominal performance.
+const LOWEST_MHZ: u64 = 200;
+const NOMINAL_MHZ: u64 = 1000;
+/// Performance level transition latency in nanoseconds.
+const LATENCY_NS: u64 = 10_000;
+
+pub const REGISTERS: &[CppcRegister] = &[
+    constant(HIGHEST_PERFORMANCE, HIGHEST),
+    constant(NOMINAL_PERFORMANCE, NOMINAL),
+    constant(LOWEST_NONLINEAR_PERFORMANCE, LOWEST_NONLINEAR),
+    constant(LOWEST_PERFORMANCE, LOWEST),
+    constant(GUARANTEED_PERFORMANCE, NOMINAL),
+    CppcRegister {
+        reg_id: DESIRED_PERFORMANCE,
+        width: 32,
+        access: CppcAccess::ReadWrite(
+            |hart_id| hart_of(hart_id).lock().desired,
+            |hart_id, value| {
+                let mut hart = hart_of(hart_id).lock();
+                // performance delivered so far is at the previous level
+                hart.update_delivered();
+                hart.desired = value.clamp(LOWEST, HIGHEST);
+            },
+        ),
+    },
+    CppcRegister {
+        reg_id: MINIMUM_PERFORMANCE,
+        width: 32,
+        access: CppcAccess::ReadWrite(
+            |hart_id| hart_of(hart_id).lock().minimum,
+            |hart_id, value| hart_of(hart_id).lock().minimum = value.clamp(LOWEST, HIGHEST),
+        ),
+    },
+    CppcRegister {
+        reg_id: MAXIMUM_PERFORMANCE,
+        width: 32,
+        access: CppcAccess::ReadWrite(
+            |hart_id| hart_of(hart_id).lock().maximum,
+            |hart_id, value| hart_of(hart_id).lock().maximum = value.clamp(LOWEST, HIGHEST),
+        ),
+    },
+    CppcRegister {
+        reg_id: REFERENCE_PERFORMANCE_COUNTER,
+        width: 64,
+        access: CppcAccess::ReadOnly(|_| mcycle::read64()),
+    },
+    CppcRegister {
+        reg_id: DELIVERED_PERFORMANCE_COUNTER,
+        width: 64,
+        access: CppcAccess::ReadOnly(|hart_id| {
+            let mut hart = hart_of(hart_id).lock();
+            hart.update_delivered();
+            hart.delivered
+        }),
+    },
+    CppcRegister {
+        reg_id: CPPC_ENABLE,
+        width: 32,
+        access: CppcAccess::ReadWrite(
+            |hart_id| hart_of(hart_id).lock().enable as u64,
+            |hart_id, value| hart_of(hart_id).lock().enable = value & 1 != 0,
+        ),
+    },
+    constant(LOWEST_FREQUENCY, LOWEST_MHZ),
+    constant(NOMINAL_FREQUENCY, NOMINAL_MHZ),
+    constant(TRANSITION_LATENCY, LATENCY_NS),
+];
+
+/// Read-only 32-bit register of a fixed value.
+const fn constant(reg_id: u32, value: u64) -> CppcRegister {
+    CppcRegister {
+        reg_id,
+        width: 32,
+        access: CppcAccess::Constant(value),
+    }
+}
+
+/// Performance control state of one hart.
+struct SimulatedHart {
+    desired: u64,
+    minimum: u64,
+    maximum: u64,
+    enable: bool,
+    /// Delivered performance counter at `mcycle` value `last_cycle`.
+    delivered: u64,
+    last_cycle: u64,
+}
+
+impl SimulatedHart {
+    const fn new() -> Self {
+        Self {
+            desired: NOMINAL,
+            minimum: LOWEST,
+            maximum: HIGHEST,
+            enable: false,
+            delivered: 0,
+            last_cycle: 0,
+        }
+    }
+
+    /// Advances delivered performance counter to current `mcycle`.
+    fn update_delivered(&mut self) {
+        let cycle = mcycle::read64();
+        let elapsed = cycle.wrapping_sub(self.last_cycle);
+        // widened so that long gaps between reads do not overflow
+        let delivered = elapsed as u128 * self.desired as u128 / NOMINAL as u128;
+        self.delivered = self.delivered.wrapping_add(delivered as u64);
+        self.last_cycle = cycle;
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SIMULATED_HART_INIT: Mutex<SimulatedHart> = Mutex::new(SimulatedHart::new());
+static HARTS: [Mutex<SimulatedHart>; NUM_HART_MAX] = [SIMULATED_HART_INIT; NUM_HART_MAX];
+
+/// Performance control state of a usable hart; only usable harts run supervisor software.
+#[inline]
+fn hart_of(hart_id: usize) -> &'static Mutex<SimulatedHart> {
+    crate::hart::entry_of(&HARTS, hart_id).expect("hart is usable")
+}
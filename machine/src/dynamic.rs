@@ -1,5 +1,8 @@
 //! Frequently used first boot stage dynamic information on RISC-V.
 
+use core::{fmt, mem::size_of, ops::RangeInclusive};
+use riscv::register::mstatus::MPP;
+
 /// M-mode firmware dynamic information.
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -14,11 +17,109 @@ pub struct DynamicInfo {
     pub next_mode: usize,
     /// M-mode firmware options; its definition varies between SBI implementations.
     pub options: usize,
+    /// Preferred boot hart ID, added in version 2; `usize::MAX` if unspecified.
+    ///
+    /// The hart winning the boot lottery hands boot over to this hart if it is usable.
+    pub boot_hart: usize,
+}
+
+/// Dynamic information magic value, i.e. "OSBI" in little endian.
+pub const DYNAMIC_INFO_MAGIC: usize = 0x4942534f;
+/// Dynamic information versions supported by this firmware.
+const DYNAMIC_INFO_VERSIONS: RangeInclusive<usize> = 1..=2;
+
+/// Error on reading dynamic information.
+#[derive(Debug)]
+pub enum DynamicError {
+    /// The address is null, misaligned, out of system memory or not readable.
+    InvalidAddress(usize),
+    /// Magic value does not match `DYNAMIC_INFO_MAGIC`.
+    InvalidMagic(usize),
+    /// Dynamic information version is not supported.
+    UnsupportedVersion(usize),
+}
+
+impl fmt::Display for DynamicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidAddress(addr) => write!(f, "invalid address 0x{:x}", addr),
+            Self::InvalidMagic(magic) => write!(f, "invalid magic 0x{:x}", magic),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+        }
+    }
+}
+
+impl DynamicInfo {
+    /// Privilege mode of the next boot-loading stage, or `None` if `next_mode` is not supported.
+    #[inline]
+    pub fn mpp(&self) -> Option<MPP> {
+        match self.next_mode {
+            0 => Some(MPP::User),
+            1 => Some(MPP::Supervisor),
+            3 => Some(MPP::Machine),
+            _ => None,
+        }
+    }
+}
+
+pub fn try_read_dynamic(paddr: usize) -> Result<DynamicInfo, DynamicError> {
+    if paddr == 0 || paddr % size_of::<usize>() != 0 {
+        return Err(DynamicError::InvalidAddress(paddr));
+    }
+    let read = |index: usize| {
+        let addr = paddr
+            .checked_add(index * size_of::<usize>())
+            .ok_or(DynamicError::InvalidAddress(paddr))?;
+        if !crate::memory::is_system_memory(addr, size_of::<usize>()) {
+            return Err(DynamicError::InvalidAddress(addr));
+        }
+        load_usize_checked(addr).ok_or(DynamicError::InvalidAddress(addr))
+    };
+    let magic = read(0)?;
+    if magic != DYNAMIC_INFO_MAGIC {
+        return Err(DynamicError::InvalidMagic(magic));
+    }
+    let version = read(1)?;
+    if !DYNAMIC_INFO_VERSIONS.contains(&version) {
+        return Err(DynamicError::UnsupportedVersion(version));
+    }
+    Ok(DynamicInfo {
+        magic,
+        version,
+        next_addr: read(2)?,
+        next_mode: read(3)?,
+        options: read(4)?,
+        boot_hart: if version >= 2 { read(5)? } else { usize::MAX },
+    })
+}
+
+/// Loads a machine word from `addr`, returning `None` on access fault instead of trapping.
+#[inline]
+fn load_usize_checked(addr: usize) -> Option<usize> {
+    let ans = unsafe { load_usize_or_fault(addr) };
+    (ans.fault == 0).then_some(ans.value)
+}
+
+#[repr(C)]
+struct LoadResult {
+    value: usize,
+    fault: usize,
 }
 
-// TODO unconstrained lifetime
-pub fn try_read_dynamic(paddr: usize) -> Result<DynamicInfo, ()> {
-    // TODO check pointer before dereference
-    let ans = unsafe { *(paddr as *const DynamicInfo) };
-    Ok(ans)
+#[naked]
+unsafe extern "C" fn load_usize_or_fault(addr: usize) -> LoadResult {
+    core::arch::asm!(
+        // temporarily redirect machine traps to label 2
+        "   csrr    t1, mtvec
+            la      t0, 2f
+            csrw    mtvec, t0
+            li      a1, 0
+            ld      a0, 0(a0)
+            j       3f",
+        ".align 2",
+        "2: li      a1, 1",
+        "3: csrw    mtvec, t1
+            ret",
+        options(noreturn)
+    )
 }
@@ -1,45 +1,97 @@
 mod clint;
+mod patch;
 mod sifive_test;
+mod syscon;
 mod uart16550;
 
-use core::ops::Range;
+use core::{fmt::Write, ops::Range, ptr::addr_of_mut};
 use dtb_walker::WalkOperation::{StepInto, StepOut, StepOver};
 use dtb_walker::{Dtb, DtbObj, HeaderError, Property};
+use patch::{FdtPatcher, PatchError};
 use rustsbi::RustSBI;
 
+/// SBI board built from the device tree, shared by all harts after boot.
+pub static BOARD: spin::Once<FdtBoard<'static>> = spin::Once::new();
+
 #[derive(RustSBI)]
 pub struct FdtBoard<'a> {
     #[rustsbi(dbcn)]
     serial: uart16550::Uart16550Handle<'a>,
     #[rustsbi(time, ipi)]
     clint: clint::ClintHandle<'a>,
+    #[cfg(feature = "hsm")]
+    #[rustsbi(hsm)]
+    hsm: crate::hsm::MachineHsm,
+    #[rustsbi(fence)]
+    rfence: crate::rfence::MachineRfence,
     #[rustsbi(reset)]
     sifive_test: sifive_test::SifiveTestHandle<'a>,
+    #[cfg(feature = "pmu")]
+    #[rustsbi(pmu)]
+    pmu: crate::pmu::MachinePmu,
+    #[cfg(feature = "susp")]
+    #[rustsbi(susp)]
+    susp: crate::susp::MachineSusp<crate::susp::WfiSleep>,
+    #[cfg(feature = "nacl")]
+    #[rustsbi(nacl)]
+    nacl: crate::nacl::MachineNacl,
+    #[cfg(feature = "sta")]
+    #[rustsbi(sta)]
+    sta: crate::sta::MachineSta,
+    #[rustsbi(cppc)]
+    cppc: Option<crate::cppc::MachineCppc>,
 }
 
+unsafe impl Send for FdtBoard<'_> {}
+unsafe impl Sync for FdtBoard<'_> {}
+
 impl<'a> FdtBoard<'a> {
     #[inline]
     pub fn new() -> Self {
         Self {
-            serial: uart16550::Uart16550Handle {
-                uart16550: None,
-                range: 0x80200000..0x90000000usize, // TODO correct physical memory range
+            serial: uart16550::Uart16550Handle { uart16550: None },
+            clint: clint::ClintHandle { clint: None },
+            #[cfg(feature = "hsm")]
+            hsm: crate::hsm::MachineHsm,
+            rfence: crate::rfence::MachineRfence,
+            #[cfg(feature = "pmu")]
+            pmu: crate::pmu::MachinePmu,
+            #[cfg(feature = "susp")]
+            susp: crate::susp::MachineSusp {
+                hook: crate::susp::WfiSleep,
             },
-            clint: clint::ClintHandle {
-                clint: None,
-                max_hart_id: crate::NUM_HART_MAX - 1,
+            #[cfg(feature = "nacl")]
+            nacl: crate::nacl::MachineNacl,
+            #[cfg(feature = "sta")]
+            sta: crate::sta::MachineSta,
+            cppc: crate::cppc::platform_registers()
+                .map(|registers| crate::cppc::MachineCppc { registers }),
+            sifive_test: sifive_test::SifiveTestHandle {
+                sifive_test: None,
+                syscon_poweroff: None,
+                syscon_reboot: None,
             },
-            sifive_test: sifive_test::SifiveTestHandle { sifive_test: None },
         }
     }
 
     #[inline]
-    fn set_uart16550_serial(&mut self, range: Range<usize>) {
-        trace!("set_uart16550_serial range = {:x?}", range);
+    fn set_uart16550_serial(&mut self, range: Range<usize>, config: uart16550::Uart16550Config) {
+        trace!(
+            "set_uart16550_serial range = {:x?}, config = {:?}",
+            range,
+            config
+        );
         // TODO check address range
+        let Some(uart16550) = (unsafe { config.bind(range.start) }) else {
+            warn!(
+                "UART16550 with reg-shift {:?} and reg-io-width {:?} is not supported, serial console disabled",
+                config.reg_shift, config.reg_io_width
+            );
+            return;
+        };
+        config.set_baud(uart16550);
         self.serial = uart16550::Uart16550Handle {
-            uart16550: Some(unsafe { &*(range.start as *const _) }),
-            range: 0x80200000..0x90000000usize, // TODO correct physical memory range
+            uart16550: Some(uart16550),
         }
     }
 
@@ -59,8 +111,17 @@ impl<'a> FdtBoard<'a> {
 
     #[inline]
     pub fn init(&self) {
-        if let Some(uart16550) = self.serial.uart16550 {
-            crate::console::load_console_uart16550(uart16550)
+        match self.serial.uart16550 {
+            Some(uart16550::Uart16550Ref::Byte(uart16550)) => {
+                crate::console::load_console_uart16550(uart16550)
+            }
+            Some(uart16550::Uart16550Ref::Word(uart16550)) => {
+                crate::console::load_console_uart16550_u32(uart16550)
+            }
+            None => {}
+        }
+        if let Some(clint) = self.clint.clint {
+            crate::clint::load_clint(clint)
         }
         if let Some(sifive_test) = self.sifive_test.sifive_test {
             crate::reset::load_reset_sifive_test(sifive_test)
@@ -83,59 +144,555 @@ pub fn try_read_fdt<'a>(fdt_paddr: usize) -> Result<Dtb<'a>, HeaderError> {
     }
 }
 
-pub fn parse_fdt(fdt: Dtb, board: &mut FdtBoard) {
-    trace!("parse_fdt begin");
-    fdt.walk(|ctx, obj| match obj {
-        DtbObj::SubNode { name } => {
-            trace!("visit SubNode {:?}", name.as_str());
-            let current = ctx.name();
-            if ctx.level() == 0 {
-                if name == "soc".into() {
-                    StepInto
-                } else {
-                    StepOver
+/// Device drivers and the `compatible` strings they bind to.
+const DRIVERS: &[(&str, Driver)] = &[
+    ("ns16550a", Driver::Uart16550),
+    ("ns16550", Driver::Uart16550),
+    ("snps,dw-apb-uart", Driver::Uart16550),
+    ("riscv,clint0", Driver::Clint),
+    ("sifive,clint0", Driver::Clint),
+    ("sifive,test1", Driver::SifiveTest),
+    ("sifive,test0", Driver::SifiveTest),
+    ("syscon-poweroff", Driver::SysconPoweroff),
+    ("syscon-reboot", Driver::SysconReboot),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Driver {
+    Uart16550,
+    Clint,
+    SifiveTest,
+    SysconPoweroff,
+    SysconReboot,
+}
+
+/// Maximum number of `reg` ranges read from a node.
+const NUM_REGS_MAX: usize = 4;
+
+/// Properties of one device tree node collected during the walk.
+#[derive(Clone, Default)]
+struct NodeInfo {
+    path: NodePath,
+    driver: Option<Driver>,
+    regs: [Range<usize>; NUM_REGS_MAX],
+    num_regs: usize,
+    disabled: bool,
+    phandle: Option<u32>,
+    regmap: Option<u32>,
+    offset: Option<u32>,
+    value: Option<u32>,
+    mask: Option<u32>,
+    uart16550: uart16550::Uart16550Config,
+    is_cpu: bool,
+    is_memory: bool,
+    has_mmu: bool,
+    no_map: bool,
+    timebase_frequency: Option<u32>,
+}
+
+impl NodeInfo {
+    /// First range of `reg` property.
+    #[inline]
+    fn reg(&self) -> Option<Range<usize>> {
+        self.regs().first().cloned()
+    }
+
+    #[inline]
+    fn regs(&self) -> &[Range<usize>] {
+        &self.regs[..self.num_regs]
+    }
+
+    /// Register update described by a `syscon-poweroff` or `syscon-reboot` node.
+    ///
+    /// If only `mask` is given it is used as the value, as Linux does.
+    #[inline]
+    fn syscon_write(&self, base: usize) -> Option<syscon::SysconWrite> {
+        let (value, mask) = match (self.value, self.mask) {
+            (Some(value), mask) => (value, mask.unwrap_or(u32::MAX)),
+            (None, Some(mask)) => (mask, u32::MAX),
+            (None, None) => return None,
+        };
+        Some(syscon::SysconWrite {
+            addr: base + self.offset? as usize,
+            value,
+            mask,
+        })
+    }
+}
+
+/// Calls `f` on every node of the device tree once all of its properties are visited.
+fn walk_nodes(fdt: &Dtb, mut f: impl FnMut(&NodeInfo)) {
+    let mut node = NodeInfo::default();
+    fdt.walk(|ctx, obj| {
+        if let (DtbObj::Property(_), 0) = (&obj, node.path.len) {
+            node.path.len = ctx.fmt_path(&mut node.path.buf).unwrap_or(0);
+        }
+        match obj {
+            // properties of a node always precede its subnodes
+            DtbObj::SubNode { name } => {
+                trace!("visit SubNode {:?}", name.as_str());
+                f(&core::mem::take(&mut node));
+            }
+            DtbObj::Property(Property::Compatible(compatible)) => {
+                node.driver = compatible.into_iter().find_map(|c| {
+                    DRIVERS
+                        .iter()
+                        .find(|(name, _)| c.as_bytes() == name.as_bytes())
+                        .map(|&(_, driver)| driver)
+                });
+            }
+            DtbObj::Property(Property::Reg(reg)) => {
+                node.num_regs = 0;
+                for (slot, range) in node.regs.iter_mut().zip(reg) {
+                    *slot = range;
+                    node.num_regs += 1;
                 }
-            } else if current == "soc".into() {
-                if name.starts_with("uart")
-                    || name.starts_with("serial")
-                    || name.starts_with("clint")
-                    || name.starts_with("test")
-                {
-                    StepInto
-                } else {
-                    StepOver
+            }
+            DtbObj::Property(Property::Status(status)) => {
+                node.disabled = !matches!(status.as_bytes(), b"okay" | b"ok");
+            }
+            DtbObj::Property(Property::PHandle(phandle)) => node.phandle = Some(phandle.value()),
+            DtbObj::Property(Property::General { name, value }) => {
+                let string = value.split(|&b| b == 0).next().unwrap_or(&[]);
+                let value = read_cells(Some(value)).map(|v| v as u32);
+                match name.as_bytes() {
+                    b"device_type" => {
+                        node.is_cpu = string == b"cpu";
+                        node.is_memory = string == b"memory";
+                    }
+                    b"no-map" => node.no_map = true,
+                    b"mmu-type" => node.has_mmu = string != b"riscv,none",
+                    b"regmap" => node.regmap = value,
+                    b"offset" => node.offset = value,
+                    b"value" => node.value = value,
+                    b"mask" => node.mask = value,
+                    b"reg-shift" => node.uart16550.reg_shift = value,
+                    b"reg-io-width" => node.uart16550.reg_io_width = value,
+                    b"clock-frequency" => node.uart16550.clock_frequency = value,
+                    b"current-speed" => node.uart16550.current_speed = value,
+                    b"timebase-frequency" => node.timebase_frequency = value,
+                    _ => {}
                 }
-            } else {
-                StepOver
-            }
-        }
-        // DtbObj::Property(Property::Model(model)) if ctx.is_root() => {
-        //     // ans.model.0 = model.as_bytes().len();
-        //     // ans.model.1[..ans.model.0].copy_from_slice(model.as_bytes());
-        //     StepOver
-        // }
-        DtbObj::Property(Property::Reg(mut reg)) => {
-            trace!("visit DtbObj::Property Property::Reg {:x?}", reg);
-            let node = ctx.name();
-            if node.starts_with("uart") || node.starts_with("serial") {
-                if let Some(range) = reg.next() {
-                    board.set_uart16550_serial(range);
+            }
+            DtbObj::Property(_) => {}
+        }
+        StepInto
+    });
+    f(&node);
+}
+
+/// Path of the console in `/chosen/stdout-path`, with alias resolved and options removed.
+fn stdout_path(fdt: &Dtb) -> Option<NodePath> {
+    let mut stdout = None;
+    fdt.walk(|ctx, obj| match obj {
+        DtbObj::SubNode { name } if ctx.is_root() && name == "chosen".into() => StepInto,
+        DtbObj::SubNode { .. } => StepOver,
+        DtbObj::Property(Property::General { name, value })
+            if !ctx.is_root()
+                && (name == "stdout-path".into() || name == "linux,stdout-path".into()) =>
+        {
+            stdout = NodePath::from_property(value);
+            StepOut
+        }
+        DtbObj::Property(_) => StepOver,
+    });
+    let stdout = stdout?;
+    if stdout.as_bytes().starts_with(b"/") {
+        return Some(stdout);
+    }
+    let mut path = None;
+    fdt.walk(|ctx, obj| match obj {
+        DtbObj::SubNode { name } if ctx.is_root() && name == "aliases".into() => StepInto,
+        DtbObj::SubNode { .. } => StepOver,
+        DtbObj::Property(Property::General { name, value })
+            if !ctx.is_root() && name.as_bytes() == stdout.as_bytes() =>
+        {
+            path = NodePath::from_property(value);
+            StepOut
+        }
+        DtbObj::Property(_) => StepOver,
+    });
+    if path.is_none() {
+        warn!("stdout-path alias {} not found", stdout.as_str());
+    }
+    path
+}
+
+/// Hardware event mapping in the first `riscv,pmu` node.
+fn pmu_event_map(fdt: &Dtb) -> Option<crate::pmu::EventMap> {
+    let mut map = crate::pmu::EventMap::default();
+    let mut is_pmu = false;
+    let mut found = None;
+    fdt.walk(|_, obj| {
+        match obj {
+            // properties of a node always precede its subnodes
+            DtbObj::SubNode { .. } => {
+                if is_pmu && found.is_none() {
+                    found = Some(core::mem::take(&mut map));
+                }
+                map = Default::default();
+                is_pmu = false;
+            }
+            DtbObj::Property(Property::Compatible(compatible)) => {
+                is_pmu = compatible.into_iter().any(|c| c.as_bytes() == b"riscv,pmu");
+            }
+            DtbObj::Property(Property::General { name, value }) => {
+                let full = match name.as_bytes() {
+                    b"riscv,event-to-mhpmcounters" => value.chunks_exact(12).any(|entry| {
+                        let [first, last, counters] = read_u32s(entry);
+                        !map.add_counters(first, last, counters)
+                    }),
+                    b"riscv,event-to-mhpmevent" => value.chunks_exact(12).any(|entry| {
+                        let [event_idx, hi, lo] = read_u32s(entry);
+                        !map.add_selector(event_idx, (hi as u64) << 32 | lo as u64)
+                    }),
+                    b"riscv,raw-event-to-mhpmcounters" => value.chunks_exact(20).any(|entry| {
+                        let [value_hi, value_lo, mask_hi, mask_lo, counters] = read_u32s(entry);
+                        let value = (value_hi as u64) << 32 | value_lo as u64;
+                        let mask = (mask_hi as u64) << 32 | mask_lo as u64;
+                        !map.add_raw_counters(value, mask, counters)
+                    }),
+                    _ => false,
+                };
+                if full {
+                    warn!("too many entries in {}, the rest ignored", name);
                 }
-                StepOut
-            } else if node.starts_with("clint") {
-                if let Some(range) = reg.next() {
-                    board.set_clint(range);
+            }
+            DtbObj::Property(_) => {}
+        }
+        StepInto
+    });
+    if is_pmu && found.is_none() {
+        found = Some(map);
+    }
+    found
+}
+
+pub fn parse_fdt(fdt: Dtb, board: &mut FdtBoard) {
+    trace!("parse_fdt begin");
+    let stdout = stdout_path(&fdt);
+    if let Some(stdout) = &stdout {
+        trace!("stdout-path = {}", stdout.as_str());
+    }
+    let mut stdout_serial = None;
+    let mut first_serial = None;
+    let mut syscon_poweroff = None;
+    let mut syscon_reboot = None;
+    let mut hart_ids = [0; crate::NUM_HART_MAX];
+    let mut num_harts = 0;
+    let mut timebase_frequency = None;
+    let mut memory_map = crate::memory::MemoryMap::default();
+    walk_nodes(&fdt, |node| {
+        if node.is_memory && !node.disabled {
+            for range in node.regs() {
+                if !memory_map.add_memory(range.clone()) {
+                    warn!("too many memory regions, {:x?} ignored", range);
                 }
-                StepOut
-            } else if node.starts_with("test") {
-                if let Some(range) = reg.next() {
-                    board.set_sifive_test(range);
+            }
+            return;
+        }
+        if node.no_map && node.path.as_bytes().starts_with(b"/reserved-memory/") {
+            for range in node.regs() {
+                if !memory_map.add_reserved(range.clone()) {
+                    warn!("too many reserved memory regions, {:x?} ignored", range);
                 }
-                StepOut
+            }
+            return;
+        }
+        // in `/cpus`, or in each cpu node
+        if timebase_frequency.is_none() {
+            timebase_frequency = node.timebase_frequency;
+        }
+        if node.is_cpu {
+            let Some(hart_id) = node.reg().map(|reg| reg.start) else {
+                return;
+            };
+            if node.disabled || !node.has_mmu {
+                info!("hart {} is disabled or has no MMU, not usable", hart_id);
+            } else if hart_ids[..num_harts].contains(&hart_id) {
+                warn!("hart {} is listed more than once in /cpus", hart_id);
+            } else if num_harts == crate::NUM_HART_MAX {
+                warn!(
+                    "hart {} exceeds maximum number of {} harts, not usable",
+                    hart_id,
+                    crate::NUM_HART_MAX
+                );
             } else {
-                StepOver
+                hart_ids[num_harts] = hart_id;
+                num_harts += 1;
             }
+            return;
+        }
+        let Some(driver) = node.driver else {
+            return;
+        };
+        if node.disabled {
+            trace!("skip disabled {:?} device", driver);
+            return;
+        }
+        match (driver, node.reg()) {
+            (Driver::Uart16550, Some(range)) => {
+                let is_stdout = stdout
+                    .as_ref()
+                    .is_some_and(|stdout| stdout.as_bytes() == node.path.as_bytes());
+                if is_stdout {
+                    stdout_serial = Some((range, node.uart16550));
+                } else if first_serial.is_none() {
+                    first_serial = Some((range, node.uart16550));
+                }
+            }
+            (Driver::Clint, Some(range)) if board.clint.clint.is_none() => board.set_clint(range),
+            (Driver::SifiveTest, Some(range)) if board.sifive_test.sifive_test.is_none() => {
+                board.set_sifive_test(range)
+            }
+            (Driver::SysconPoweroff, _) => syscon_poweroff = Some(node.clone()),
+            (Driver::SysconReboot, _) => syscon_reboot = Some(node.clone()),
+            _ => {}
         }
-        DtbObj::Property(_) => StepOver,
     });
+    match (stdout_serial, first_serial) {
+        (Some((range, config)), _) => board.set_uart16550_serial(range, config),
+        (None, Some((range, config))) => {
+            if let Some(stdout) = &stdout {
+                warn!(
+                    "stdout-path {} is not a supported serial, using the first one",
+                    stdout.as_str()
+                );
+            }
+            board.set_uart16550_serial(range, config)
+        }
+        (None, None) => {}
+    }
+    if memory_map.is_empty() {
+        warn!("no memory node found in device tree");
+    } else {
+        trace!("{:x?}", memory_map);
+        crate::memory::load_memory_map(memory_map);
+    }
+    match timebase_frequency {
+        Some(frequency) if frequency != 0 => {
+            trace!("timebase frequency {} Hz", frequency);
+            crate::clint::load_timebase_frequency(frequency as u64);
+        }
+        _ => warn!("no timebase frequency found in /cpus"),
+    }
+    match pmu_event_map(&fdt) {
+        Some(map) if !map.is_empty() => {
+            trace!("{:x?}", map);
+            crate::pmu::load_event_map(map);
+        }
+        _ => trace!("no hardware event mapping for PMU in device tree"),
+    }
+    if num_harts == 0 {
+        warn!("no usable hart found in /cpus");
+    } else {
+        trace!("usable harts {:?}", &hart_ids[..num_harts]);
+        crate::hart::load_hart_ids(&hart_ids[..num_harts]);
+    }
+    // syscon nodes refer to their system controller by phandle, which may appear later
+    if syscon_poweroff.is_some() || syscon_reboot.is_some() {
+        walk_nodes(&fdt, |node| {
+            let (Some(phandle), Some(reg)) = (node.phandle, node.reg()) else {
+                return;
+            };
+            for (syscon, target) in [
+                (&syscon_poweroff, &mut board.sifive_test.syscon_poweroff),
+                (&syscon_reboot, &mut board.sifive_test.syscon_reboot),
+            ] {
+                if let Some(syscon) = syscon.as_ref().filter(|s| s.regmap == Some(phandle)) {
+                    *target = syscon.syscon_write(reg.start);
+                    trace!("syscon reset register {:x?}", target);
+                }
+            }
+        });
+    }
+}
+
+const LEN_FDT_BUFFER: usize = 64 * 1024;
+
+#[repr(C, align(8))]
+struct FdtBuffer([u8; LEN_FDT_BUFFER]);
+
+/// Patched device tree passed to the next stage.
+///
+/// It resides out of firmware memory so that supervisor software can read it.
+#[link_section = ".fdt"]
+static mut FDT_BUFFER: FdtBuffer = FdtBuffer([0; LEN_FDT_BUFFER]);
+
+/// Copies device tree at `fdt_paddr` and patches it for the next stage, returning address of the copy.
+///
+/// Firmware memory is added into `/reserved-memory` as a `no-map` region, devices owned by
+/// machine mode are hidden, and `/chosen` is created if absent.
+pub fn patch_fdt(fdt_paddr: usize, total_size: usize) -> Result<usize, PatchError> {
+    let src = unsafe { core::slice::from_raw_parts(fdt_paddr as *const u8, total_size) };
+    let buf = unsafe { &mut (*addr_of_mut!(FDT_BUFFER)).0 };
+    let patched_paddr = buf.as_ptr() as usize;
+    let mut fdt = FdtPatcher::new(buf, src)?;
+    reserve_firmware(&mut fdt)?;
+    hide_machine_devices(&mut fdt)?;
+    if fdt.path_offset("/chosen").is_none() {
+        let root = fdt.path_offset("/").ok_or(PatchError::BadStructure)?;
+        fdt.add_subnode(root, "chosen")?;
+    }
+    trace!(
+        "patched device tree at 0x{:x}, size {} bytes",
+        patched_paddr,
+        fdt.total_size()
+    );
+    Ok(patched_paddr)
+}
+
+fn reserve_firmware(fdt: &mut FdtPatcher) -> Result<(), PatchError> {
+    let region = crate::pmp::firmware_region();
+    let root = fdt.path_offset("/").ok_or(PatchError::BadStructure)?;
+    let reserved = match fdt.subnode_offset(root, "reserved-memory") {
+        Some(node) => node,
+        None => {
+            let node = fdt.add_subnode(root, "reserved-memory")?;
+            fdt.set_property(node, "#address-cells", &2u32.to_be_bytes())?;
+            fdt.set_property(node, "#size-cells", &2u32.to_be_bytes())?;
+            fdt.set_property(node, "ranges", &[])?;
+            node
+        }
+    };
+    let address_cells = read_cells(fdt.property(reserved, "#address-cells")).unwrap_or(2);
+    let size_cells = read_cells(fdt.property(reserved, "#size-cells")).unwrap_or(1);
+    let mut reg = [0u8; 16];
+    let mut len = 0;
+    for (value, cells) in [
+        (region.start, address_cells),
+        (region.end - region.start, size_cells),
+    ] {
+        let bytes = (value as u64).to_be_bytes();
+        let cells = cells.min(2);
+        reg[len..len + cells * 4].copy_from_slice(&bytes[8 - cells * 4..]);
+        len += cells * 4;
+    }
+    let mut name = NodePath::default();
+    write!(name, "mmode_resv@{:x}", region.start).unwrap();
+    let node = fdt.add_subnode(reserved, name.as_str())?;
+    fdt.set_property(node, "reg", &reg[..len])?;
+    fdt.set_property(node, "no-map", &[])?;
+    Ok(())
+}
+
+/// Devices used by machine-mode firmware only, disabled in the supervisor device tree.
+const MACHINE_ONLY_COMPATIBLE: &[&str] = &[
+    "riscv,clint0",
+    "sifive,clint0",
+    "riscv,aclint-mswi",
+    "riscv,aclint-mtimer",
+    "sifive,test0",
+    "sifive,test1",
+];
+const PLIC_COMPATIBLE: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+/// Machine external interrupt, i.e. `mip.MEIP`.
+const IRQ_M_EXT: u32 = 11;
+
+fn hide_machine_devices(fdt: &mut FdtPatcher) -> Result<(), PatchError> {
+    let mut node = fdt.path_offset("/").ok_or(PatchError::BadStructure)?;
+    while let Some(next) = fdt.next_node(node) {
+        node = next;
+        let Some(compatible) = fdt.property(node, "compatible") else {
+            continue;
+        };
+        if is_compatible(compatible, MACHINE_ONLY_COMPATIBLE) {
+            fdt.set_property(node, "status", b"disabled\0")?;
+        } else if is_compatible(compatible, PLIC_COMPATIBLE) {
+            hide_plic_machine_contexts(fdt, node)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces interrupt numbers of M-mode PLIC contexts with -1, keeping context indexes.
+fn hide_plic_machine_contexts(fdt: &mut FdtPatcher, node: usize) -> Result<(), PatchError> {
+    let mut cells = [0u8; 1024];
+    let Some(value) = fdt.property(node, "interrupts-extended") else {
+        return Ok(());
+    };
+    let Some(cells) = cells.get_mut(..value.len()) else {
+        warn!("too many PLIC contexts to hide machine-mode ones");
+        return Ok(());
+    };
+    cells.copy_from_slice(value);
+    // each context is a pair of interrupt controller phandle and interrupt number
+    for pair in cells.chunks_exact_mut(8) {
+        if pair[4..] == IRQ_M_EXT.to_be_bytes() {
+            pair[4..].copy_from_slice(&u32::MAX.to_be_bytes());
+        }
+    }
+    fdt.set_property(node, "interrupts-extended", cells)
+}
+
+#[inline]
+fn is_compatible(compatible: &[u8], list: &[&str]) -> bool {
+    compatible
+        .split(|&b| b == 0)
+        .any(|s| list.iter().any(|c| c.as_bytes() == s))
+}
+
+#[inline]
+fn read_cells(value: Option<&[u8]>) -> Option<usize> {
+    let value: [u8; 4] = value?.try_into().ok()?;
+    Some(u32::from_be_bytes(value) as usize)
+}
+
+/// Big-endian cells of a fixed-size property entry.
+#[inline]
+fn read_u32s<const N: usize>(entry: &[u8]) -> [u32; N] {
+    let mut cells = [0; N];
+    for (cell, bytes) in cells.iter_mut().zip(entry.chunks_exact(4)) {
+        *cell = u32::from_be_bytes(bytes.try_into().unwrap());
+    }
+    cells
+}
+
+/// Node name or path formatted without allocation.
+#[derive(Clone)]
+struct NodePath {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl NodePath {
+    /// Path from a string property, without its terminating NUL and `:options` suffix.
+    #[inline]
+    fn from_property(value: &[u8]) -> Option<Self> {
+        let value = value.split(|&b| b == 0 || b == b':').next()?;
+        let mut path = Self::default();
+        path.buf.get_mut(..value.len())?.copy_from_slice(value);
+        path.len = value.len();
+        Some(path)
+    }
+
+    #[inline]
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    #[inline]
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap_or("<invalid>")
+    }
+}
+
+impl Default for NodePath {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            buf: [0; 128],
+            len: 0,
+        }
+    }
+}
+
+impl Write for NodePath {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        let dst = self.buf.get_mut(self.len..end).ok_or(core::fmt::Error)?;
+        dst.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
 }
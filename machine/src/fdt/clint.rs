@@ -1,16 +1,23 @@
-use rustsbi::SbiRet;
+use rustsbi::{spec::pmu::firmware_event, SbiRet};
 
 pub struct ClintHandle<'a> {
     pub clint: Option<&'a aclint::SifiveClint>,
-    pub max_hart_id: usize,
 }
 
 impl<'a> rustsbi::Timer for ClintHandle<'a> {
     #[inline]
     fn set_timer(&self, stime_value: u64) {
-        if let Some(clint) = self.clint {
-            let current_hart_id = riscv::register::mhartid::read();
+        crate::pmu::record(firmware_event::SET_TIMER);
+        let current_hart_id = riscv::register::mhartid::read();
+        if crate::hart::has_sstc(current_hart_id) {
+            // stimecmp
+            unsafe { core::arch::asm!("csrw 0x14d, {}", in(reg) stime_value) };
+        } else if let Some(clint) = self.clint {
             clint.write_mtimecmp(current_hart_id, stime_value);
+            unsafe {
+                riscv::register::mip::clear_stimer();
+                riscv::register::mie::set_mtimer();
+            }
         } else {
             debug!("SBI TIME set_timer when no CLINT peripheral in handle")
         }
@@ -20,10 +27,14 @@ impl<'a> rustsbi::Timer for ClintHandle<'a> {
 impl<'a> rustsbi::Ipi for ClintHandle<'a> {
     #[inline]
     fn send_ipi(&self, hart_mask: rustsbi::HartMask) -> SbiRet {
-        if let Some(clint) = self.clint {
-            for hart_id in 0..=self.max_hart_id {
+        if self.clint.is_some() {
+            if !crate::hart::is_valid_mask(hart_mask) {
+                return SbiRet::invalid_param();
+            }
+            for hart_id in crate::hart::hart_ids() {
                 if hart_mask.has_bit(hart_id) {
-                    clint.set_msip(hart_id);
+                    crate::clint::send_ipi(hart_id, crate::clint::IPI_SUPERVISOR);
+                    crate::pmu::record(firmware_event::IPI_SENT);
                 }
             }
             SbiRet::success(0)
@@ -0,0 +1,391 @@
+//! Minimal flattened device tree editor for patching the copy passed to the next stage.
+//!
+//! The copy is laid out as header, memory reservation block, structure block and strings
+//! block in this order, so the structure block can grow by shifting strings towards the
+//! spare space at the end of the buffer.
+
+use core::fmt;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+const LEN_HEADER: usize = 40;
+const OFF_TOTALSIZE: usize = 4;
+const OFF_DT_STRUCT: usize = 8;
+const OFF_DT_STRINGS: usize = 12;
+const OFF_MEM_RSVMAP: usize = 16;
+const OFF_VERSION: usize = 20;
+const OFF_LAST_COMP_VERSION: usize = 24;
+const OFF_SIZE_DT_STRINGS: usize = 32;
+const OFF_SIZE_DT_STRUCT: usize = 36;
+
+/// Error on patching device tree.
+#[derive(Debug)]
+pub enum PatchError {
+    /// Source device tree header is invalid or its version is older than 17.
+    BadHeader,
+    /// Structure block has an unexpected token or runs out of bounds.
+    BadStructure,
+    /// The patched device tree does not fit into the buffer.
+    NoSpace,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadHeader => write!(f, "bad device tree header"),
+            Self::BadStructure => write!(f, "bad device tree structure"),
+            Self::NoSpace => write!(f, "no space left in device tree buffer"),
+        }
+    }
+}
+
+/// Structure block token.
+enum Token<'a> {
+    BeginNode(&'a [u8]),
+    EndNode,
+    Prop { name_off: usize, value: &'a [u8] },
+    Nop,
+    End,
+}
+
+/// Device tree being edited in a writable buffer.
+pub struct FdtPatcher<'a> {
+    buf: &'a mut [u8],
+}
+
+impl<'a> FdtPatcher<'a> {
+    /// Copies the device tree `src` into `buf` for patching.
+    pub fn new(buf: &'a mut [u8], src: &[u8]) -> Result<Self, PatchError> {
+        if src.len() < LEN_HEADER || read_u32(src, 0) != FDT_MAGIC {
+            return Err(PatchError::BadHeader);
+        }
+        if read_u32(src, OFF_VERSION) < 17 {
+            return Err(PatchError::BadHeader);
+        }
+        let block = |off_field: usize, size_field: usize| {
+            let start = read_u32(src, off_field) as usize;
+            let size = read_u32(src, size_field) as usize;
+            src.get(start..start + size).ok_or(PatchError::BadHeader)
+        };
+        // memory reservation block ends with an all-zero entry
+        let rsvmap_start = read_u32(src, OFF_MEM_RSVMAP) as usize;
+        let mut rsvmap_len = 0;
+        loop {
+            let entry = src
+                .get(rsvmap_start + rsvmap_len..rsvmap_start + rsvmap_len + 16)
+                .ok_or(PatchError::BadHeader)?;
+            rsvmap_len += 16;
+            if entry.iter().all(|&b| b == 0) {
+                break;
+            }
+        }
+        let rsvmap = &src[rsvmap_start..rsvmap_start + rsvmap_len];
+        let dt_struct = block(OFF_DT_STRUCT, OFF_SIZE_DT_STRUCT)?;
+        let dt_strings = block(OFF_DT_STRINGS, OFF_SIZE_DT_STRINGS)?;
+
+        let off_rsvmap = LEN_HEADER;
+        let off_struct = off_rsvmap + rsvmap.len();
+        let off_strings = off_struct + dt_struct.len();
+        let total = off_strings + dt_strings.len();
+        if total > buf.len() {
+            return Err(PatchError::NoSpace);
+        }
+        buf[..LEN_HEADER].copy_from_slice(&src[..LEN_HEADER]);
+        buf[off_rsvmap..off_struct].copy_from_slice(rsvmap);
+        buf[off_struct..off_strings].copy_from_slice(dt_struct);
+        buf[off_strings..total].copy_from_slice(dt_strings);
+        let mut ans = Self { buf };
+        ans.set_header(OFF_TOTALSIZE, total);
+        ans.set_header(OFF_MEM_RSVMAP, off_rsvmap);
+        ans.set_header(OFF_DT_STRUCT, off_struct);
+        ans.set_header(OFF_DT_STRINGS, off_strings);
+        ans.set_header(OFF_VERSION, 17);
+        ans.set_header(OFF_LAST_COMP_VERSION, 16);
+        Ok(ans)
+    }
+
+    /// Size of the patched device tree in bytes.
+    #[inline]
+    pub fn total_size(&self) -> usize {
+        self.header(OFF_TOTALSIZE)
+    }
+
+    /// Offset of the node at absolute `path`, e.g. `/chosen`.
+    pub fn path_offset(&self, path: &str) -> Option<usize> {
+        let mut node = self.root_offset()?;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            node = self.subnode_offset(node, name)?;
+        }
+        Some(node)
+    }
+
+    /// Offset of the direct child of `parent` named `name`.
+    ///
+    /// A `name` without unit address also matches children with one.
+    pub fn subnode_offset(&self, parent: usize, name: &str) -> Option<usize> {
+        let name = name.as_bytes();
+        let mut off = self.node_body(parent)?;
+        let mut depth = 0usize;
+        loop {
+            let (token, next) = self.token(off).ok()?;
+            match token {
+                Token::BeginNode(node_name) => {
+                    let matches = node_name == name
+                        || (!name.contains(&b'@')
+                            && node_name.split(|&b| b == b'@').next() == Some(name));
+                    if depth == 0 && matches {
+                        return Some(off);
+                    }
+                    depth += 1;
+                }
+                Token::EndNode if depth == 0 => return None,
+                Token::EndNode => depth -= 1,
+                Token::End => return None,
+                Token::Prop { .. } | Token::Nop => {}
+            }
+            off = next;
+        }
+    }
+
+    /// Offset of the node after `node` in depth-first order, or `None` at the end of the tree.
+    pub fn next_node(&self, node: usize) -> Option<usize> {
+        let mut off = self.node_body(node)?;
+        loop {
+            match self.token(off).ok()? {
+                (Token::BeginNode(_), _) => return Some(off),
+                (Token::End, _) => return None,
+                (_, next) => off = next,
+            }
+        }
+    }
+
+    /// Value of property `name` in node at `node`.
+    pub fn property(&self, node: usize, name: &str) -> Option<&[u8]> {
+        let (prop, _) = self.property_offset(node, name)?;
+        match self.token(prop).ok()?.0 {
+            Token::Prop { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Sets property `name` of node at `node` to `value`, adding it if absent.
+    pub fn set_property(
+        &mut self,
+        node: usize,
+        name: &str,
+        value: &[u8],
+    ) -> Result<(), PatchError> {
+        let new_len = 12 + align4(value.len());
+        let (off, name_off) = match self.property_offset(node, name) {
+            Some((off, next)) => {
+                let name_off = self.read_struct(off + 8) as usize;
+                self.resize_struct(off, next - off, new_len)?;
+                (off, name_off)
+            }
+            None => {
+                let name_off = self.string_offset(name)?;
+                let off = self.node_body(node).ok_or(PatchError::BadStructure)?;
+                self.resize_struct(off, 0, new_len)?;
+                (off, name_off)
+            }
+        };
+        self.write_struct(off, FDT_PROP);
+        self.write_struct(off + 4, value.len() as u32);
+        self.write_struct(off + 8, name_off as u32);
+        let start = self.header(OFF_DT_STRUCT) + off + 12;
+        self.buf[start..start + value.len()].copy_from_slice(value);
+        self.buf[start + value.len()..start + align4(value.len())].fill(0);
+        Ok(())
+    }
+
+    /// Adds an empty child node `name` at the end of node `parent`, returning its offset.
+    pub fn add_subnode(&mut self, parent: usize, name: &str) -> Result<usize, PatchError> {
+        let end = self.node_end(parent).ok_or(PatchError::BadStructure)?;
+        let name_len = align4(name.len() + 1);
+        self.resize_struct(end, 0, 4 + name_len + 4)?;
+        self.write_struct(end, FDT_BEGIN_NODE);
+        let start = self.header(OFF_DT_STRUCT) + end + 4;
+        self.buf[start..start + name.len()].copy_from_slice(name.as_bytes());
+        self.buf[start + name.len()..start + name_len].fill(0);
+        self.write_struct(end + 4 + name_len, FDT_END_NODE);
+        Ok(end)
+    }
+
+    /// Offset of the root node, skipping leading `FDT_NOP` tokens.
+    fn root_offset(&self) -> Option<usize> {
+        let mut off = 0;
+        loop {
+            match self.token(off).ok()? {
+                (Token::BeginNode(_), _) => return Some(off),
+                (Token::Nop, next) => off = next,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Offset right after the `FDT_BEGIN_NODE` token and name of node at `node`.
+    fn node_body(&self, node: usize) -> Option<usize> {
+        match self.token(node).ok()? {
+            (Token::BeginNode(_), next) => Some(next),
+            _ => None,
+        }
+    }
+
+    /// Offset of the `FDT_END_NODE` token closing node at `node`.
+    fn node_end(&self, node: usize) -> Option<usize> {
+        let mut off = self.node_body(node)?;
+        let mut depth = 0usize;
+        loop {
+            let (token, next) = self.token(off).ok()?;
+            match token {
+                Token::BeginNode(_) => depth += 1,
+                Token::EndNode if depth == 0 => return Some(off),
+                Token::EndNode => depth -= 1,
+                Token::End => return None,
+                Token::Prop { .. } | Token::Nop => {}
+            }
+            off = next;
+        }
+    }
+
+    /// Offsets of property `name` of node at `node` and of the token after it.
+    fn property_offset(&self, node: usize, name: &str) -> Option<(usize, usize)> {
+        let mut off = self.node_body(node)?;
+        loop {
+            let (token, next) = self.token(off).ok()?;
+            match token {
+                Token::Prop { name_off, .. } if self.string(name_off)? == name.as_bytes() => {
+                    return Some((off, next))
+                }
+                Token::Prop { .. } | Token::Nop => off = next,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Parses the token at structure block offset `off`.
+    fn token(&self, off: usize) -> Result<(Token<'_>, usize), PatchError> {
+        let size = self.header(OFF_SIZE_DT_STRUCT);
+        let block = &self.buf[self.header(OFF_DT_STRUCT)..][..size];
+        let word = |off: usize| {
+            block
+                .get(off..off + 4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or(PatchError::BadStructure)
+        };
+        match word(off)? {
+            FDT_BEGIN_NODE => {
+                let rest = block.get(off + 4..).ok_or(PatchError::BadStructure)?;
+                let len = rest
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or(PatchError::BadStructure)?;
+                Ok((Token::BeginNode(&rest[..len]), off + 4 + align4(len + 1)))
+            }
+            FDT_END_NODE => Ok((Token::EndNode, off + 4)),
+            FDT_PROP => {
+                let len = word(off + 4)? as usize;
+                let name_off = word(off + 8)? as usize;
+                let value = block
+                    .get(off + 12..off + 12 + len)
+                    .ok_or(PatchError::BadStructure)?;
+                Ok((Token::Prop { name_off, value }, off + 12 + align4(len)))
+            }
+            FDT_NOP => Ok((Token::Nop, off + 4)),
+            FDT_END => Ok((Token::End, off + 4)),
+            _ => Err(PatchError::BadStructure),
+        }
+    }
+
+    /// NUL-terminated string at `name_off` of the strings block, without the NUL.
+    fn string(&self, name_off: usize) -> Option<&[u8]> {
+        let start = self.header(OFF_DT_STRINGS);
+        let strings = &self.buf[start..start + self.header(OFF_SIZE_DT_STRINGS)];
+        let rest = strings.get(name_off..)?;
+        Some(&rest[..rest.iter().position(|&b| b == 0)?])
+    }
+
+    /// Offset of `name` in the strings block, appending it if absent.
+    fn string_offset(&mut self, name: &str) -> Result<usize, PatchError> {
+        let start = self.header(OFF_DT_STRINGS);
+        let size = self.header(OFF_SIZE_DT_STRINGS);
+        let strings = &self.buf[start..start + size];
+        let mut off = 0;
+        for s in strings.split(|&b| b == 0) {
+            if s == name.as_bytes() && off + s.len() < size {
+                return Ok(off);
+            }
+            off += s.len() + 1;
+        }
+        // strings block is the last block, so it can grow in place
+        let total = self.total_size();
+        let new_total = total + name.len() + 1;
+        if new_total > self.buf.len() {
+            return Err(PatchError::NoSpace);
+        }
+        self.buf[total..total + name.len()].copy_from_slice(name.as_bytes());
+        self.buf[total + name.len()] = 0;
+        self.set_header(OFF_SIZE_DT_STRINGS, size + name.len() + 1);
+        self.set_header(OFF_TOTALSIZE, new_total);
+        Ok(size)
+    }
+
+    /// Replaces `old_len` bytes at structure block offset `off` with `new_len` bytes of space.
+    fn resize_struct(
+        &mut self,
+        off: usize,
+        old_len: usize,
+        new_len: usize,
+    ) -> Result<(), PatchError> {
+        let total = self.total_size();
+        let new_total = total - old_len + new_len;
+        if new_total > self.buf.len() {
+            return Err(PatchError::NoSpace);
+        }
+        let start = self.header(OFF_DT_STRUCT) + off;
+        self.buf
+            .copy_within(start + old_len..total, start + new_len);
+        let size_struct = self.header(OFF_SIZE_DT_STRUCT);
+        let off_strings = self.header(OFF_DT_STRINGS);
+        self.set_header(OFF_SIZE_DT_STRUCT, size_struct - old_len + new_len);
+        self.set_header(OFF_DT_STRINGS, off_strings - old_len + new_len);
+        self.set_header(OFF_TOTALSIZE, new_total);
+        Ok(())
+    }
+
+    #[inline]
+    fn header(&self, off: usize) -> usize {
+        read_u32(self.buf, off) as usize
+    }
+
+    #[inline]
+    fn set_header(&mut self, off: usize, value: usize) {
+        self.buf[off..off + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    }
+
+    #[inline]
+    fn read_struct(&self, off: usize) -> u32 {
+        read_u32(self.buf, self.header(OFF_DT_STRUCT) + off)
+    }
+
+    #[inline]
+    fn write_struct(&mut self, off: usize, value: u32) {
+        let start = self.header(OFF_DT_STRUCT) + off;
+        self.buf[start..start + 4].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+#[inline]
+fn read_u32(buf: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+#[inline]
+const fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
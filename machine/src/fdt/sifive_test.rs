@@ -7,8 +7,14 @@ use rustsbi::{
 };
 use sifive_test_device::SifiveTestDevice;
 
+use super::syscon::SysconWrite;
+
 pub struct SifiveTestHandle<'a> {
     pub sifive_test: Option<&'a SifiveTestDevice>,
+    /// Used when there is no SiFive test device.
+    pub syscon_poweroff: Option<SysconWrite>,
+    /// Used when there is no SiFive test device.
+    pub syscon_reboot: Option<SysconWrite>,
 }
 
 impl<'a> Reset for SifiveTestHandle<'a> {
@@ -27,7 +33,19 @@ impl<'a> Reset for SifiveTestHandle<'a> {
                 _ => SbiRet::invalid_param(),
             }
         } else {
-            SbiRet::not_supported()
+            let syscon = match reset_type {
+                RESET_TYPE_SHUTDOWN => self.syscon_poweroff,
+                RESET_TYPE_COLD_REBOOT | RESET_TYPE_WARM_REBOOT => self.syscon_reboot,
+                _ => return SbiRet::invalid_param(),
+            };
+            match syscon {
+                Some(syscon) => {
+                    syscon.write();
+                    // reset did not happen
+                    SbiRet::failed()
+                }
+                None => SbiRet::not_supported(),
+            }
         }
     }
 }
@@ -0,0 +1,24 @@
+//! FDT `syscon-reboot` and `syscon-poweroff` driver module
+
+/// Register update on a system controller that resets or powers off the platform.
+#[derive(Clone, Copy, Debug)]
+pub struct SysconWrite {
+    pub addr: usize,
+    pub value: u32,
+    pub mask: u32,
+}
+
+impl SysconWrite {
+    #[inline]
+    pub fn write(&self) {
+        let reg = self.addr as *mut u32;
+        unsafe {
+            let old = if self.mask == u32::MAX {
+                0
+            } else {
+                reg.read_volatile()
+            };
+            reg.write_volatile(old & !self.mask | self.value & self.mask);
+        }
+    }
+}
@@ -1,20 +1,109 @@
 //! FDT UART 16550 driver module
 
-use core::ops::Range;
 use rustsbi::{Physical, SbiRet};
-use uart16550::Uart16550;
+use uart16550::{LineControl, Register, Uart16550};
 
 pub struct Uart16550Handle<'a> {
-    pub uart16550: Option<&'a Uart16550<u8>>,
-    pub range: Range<usize>,
+    pub uart16550: Option<Uart16550Ref<'a>>,
+}
+
+/// UART 16550 with byte-spaced 8-bit or word-spaced 32-bit registers.
+#[derive(Clone, Copy)]
+pub enum Uart16550Ref<'a> {
+    Byte(&'a Uart16550<u8>),
+    Word(&'a Uart16550<u32>),
+}
+
+impl<'a> Uart16550Ref<'a> {
+    #[inline]
+    pub fn write(&self, buf: &[u8]) -> usize {
+        match self {
+            Self::Byte(uart16550) => uart16550.write(buf),
+            Self::Word(uart16550) => uart16550.write(buf),
+        }
+    }
+
+    #[inline]
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        match self {
+            Self::Byte(uart16550) => uart16550.read(buf),
+            Self::Word(uart16550) => uart16550.read(buf),
+        }
+    }
+
+    /// Sets 8N1 frame format with baud rate divisor.
+    #[inline]
+    fn set_divisor(&self, divisor: u16) {
+        fn set<R: Register>(uart16550: &Uart16550<R>, divisor: u16) {
+            uart16550.lcr().write(LineControl::CONFIG_8N1);
+            uart16550.write_divisor(divisor);
+        }
+        match self {
+            Self::Byte(uart16550) => set(uart16550, divisor),
+            Self::Word(uart16550) => set(uart16550, divisor),
+        }
+    }
+}
+
+/// Register layout and clock of a UART node, from `reg-shift`, `reg-io-width`,
+/// `clock-frequency` and `current-speed` properties.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Uart16550Config {
+    pub reg_shift: Option<u32>,
+    pub reg_io_width: Option<u32>,
+    pub clock_frequency: Option<u32>,
+    pub current_speed: Option<u32>,
+}
+
+impl Uart16550Config {
+    /// Binds the UART at `base`, or returns `None` if its register layout is not supported.
+    ///
+    /// Only byte-spaced 8-bit (`reg-shift` 0, `reg-io-width` 1) and word-spaced 32-bit
+    /// (`reg-shift` 2, `reg-io-width` 4) registers can be accessed by the driver.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to UART 16550 registers described by this config.
+    pub unsafe fn bind<'a>(&self, base: usize) -> Option<Uart16550Ref<'a>> {
+        match (self.reg_shift.unwrap_or(0), self.reg_io_width.unwrap_or(1)) {
+            (0, 1) => Some(Uart16550Ref::Byte(&*(base as *const _))),
+            (2, 4) => Some(Uart16550Ref::Word(&*(base as *const _))),
+            _ => None,
+        }
+    }
+
+    /// Programs baud rate from `current-speed` if the input clock is known.
+    pub fn set_baud(&self, uart16550: Uart16550Ref) {
+        let (Some(clock), Some(baud)) = (self.clock_frequency, self.current_speed) else {
+            return;
+        };
+        if clock == 0 || baud == 0 {
+            return;
+        }
+        let (clock, baud) = (u64::from(clock), u64::from(baud));
+        let divisor = (clock + baud * 8) / (baud * 16);
+        match u16::try_from(divisor) {
+            Ok(divisor) if divisor != 0 => {
+                trace!(
+                    "UART clock {} Hz, baud {}, divisor {}",
+                    clock,
+                    baud,
+                    divisor
+                );
+                uart16550.set_divisor(divisor)
+            }
+            _ => warn!("UART clock {} Hz cannot produce baud {}", clock, baud),
+        }
+    }
 }
 
 impl<'a> rustsbi::Console for Uart16550Handle<'a> {
     fn write(&self, bytes: Physical<&[u8]>) -> SbiRet {
         if let Some(uart16550) = self.uart16550 {
             let start = bytes.phys_addr_lo();
-            let end = start + bytes.num_bytes();
-            if self.range.contains(&start) && self.range.contains(&(end - 1)) {
+            if bytes.phys_addr_hi() == 0
+                && crate::memory::is_supervisor_memory(start, bytes.num_bytes())
+            {
                 let buf =
                     unsafe { core::slice::from_raw_parts(start as *const u8, bytes.num_bytes()) };
                 SbiRet::success(uart16550.write(buf))
@@ -26,8 +115,22 @@ impl<'a> rustsbi::Console for Uart16550Handle<'a> {
         }
     }
 
-    fn read(&self, _bytes: Physical<&mut [u8]>) -> SbiRet {
-        todo!()
+    fn read(&self, bytes: Physical<&mut [u8]>) -> SbiRet {
+        if let Some(uart16550) = self.uart16550 {
+            let start = bytes.phys_addr_lo();
+            if bytes.phys_addr_hi() == 0
+                && crate::memory::is_supervisor_memory(start, bytes.num_bytes())
+            {
+                let buf =
+                    unsafe { core::slice::from_raw_parts_mut(start as *mut u8, bytes.num_bytes()) };
+                // returns immediately with bytes already received
+                SbiRet::success(uart16550.read(buf))
+            } else {
+                SbiRet::invalid_param()
+            }
+        } else {
+            SbiRet::failed()
+        }
     }
 
     fn write_byte(&self, byte: u8) -> SbiRet {
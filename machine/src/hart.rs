@@ -0,0 +1,210 @@
+//! Per-hart machine-mode environment set up before entering supervisor software.
+
+use crate::NUM_HART_MAX;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::{mcounteren, medeleg, mideleg, mie, misa};
+use rustsbi::HartMask;
+
+/// `menvcfg.STCE`, enabling supervisor timer compare (Sstc) when implemented.
+const MENVCFG_STCE: usize = 1 << 63;
+/// `medeleg` bits of VS-mode ecall, guest page faults and virtual instruction,
+/// handled by a hypervisor in HS-mode.
+const MEDELEG_HYPERVISOR: usize = (1 << 10) | (1 << 20) | (1 << 21) | (1 << 22) | (1 << 23);
+/// `mideleg` bits of VS-level interrupts and supervisor guest external interrupt;
+/// read-only one on harts with the H extension, set in case they are not.
+const MIDELEG_HYPERVISOR: usize = (1 << 2) | (1 << 6) | (1 << 10) | (1 << 12);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SSTC_INIT: AtomicBool = AtomicBool::new(false);
+static SSTC: [AtomicBool; NUM_HART_MAX] = [SSTC_INIT; NUM_HART_MAX];
+
+/// Usable harts; the index of a hart is its position in the list, and per-hart tables
+/// of firmware are indexed by it so that hart IDs need not be dense.
+///
+/// Until a hart list is loaded, all harts below `NUM_HART_MAX` are usable by their ID.
+#[allow(clippy::declare_interior_mutable_const)]
+const HART_ID_INIT: AtomicUsize = AtomicUsize::new(0);
+static HART_IDS: [AtomicUsize; NUM_HART_MAX] = [HART_ID_INIT; NUM_HART_MAX];
+static NUM_HARTS: AtomicUsize = AtomicUsize::new(0);
+static HART_LIST_LOADED: AtomicBool = AtomicBool::new(false);
+
+/// Sets usable harts, at most `NUM_HART_MAX` of them.
+#[cfg(feature = "fdt")]
+pub fn load_hart_ids(hart_ids: &[usize]) {
+    for (slot, &hart_id) in HART_IDS.iter().zip(hart_ids) {
+        slot.store(hart_id, Ordering::Relaxed);
+    }
+    NUM_HARTS.store(hart_ids.len().min(NUM_HART_MAX), Ordering::Relaxed);
+    HART_LIST_LOADED.store(true, Ordering::Release);
+}
+
+/// Index of the hart among usable harts, or `None` if the hart may not run supervisor software.
+#[inline]
+pub fn index_of(hart_id: usize) -> Option<usize> {
+    if HART_LIST_LOADED.load(Ordering::Acquire) {
+        HART_IDS[..NUM_HARTS.load(Ordering::Relaxed)]
+            .iter()
+            .position(|id| id.load(Ordering::Relaxed) == hart_id)
+    } else {
+        (hart_id < NUM_HART_MAX).then_some(hart_id)
+    }
+}
+
+/// Whether every hart in `hart_mask` is usable; `hart_mask_base` of all ones selects all harts.
+pub fn is_valid_mask(hart_mask: HartMask) -> bool {
+    let (mask, base) = hart_mask.into_inner();
+    if base == usize::MAX {
+        return true;
+    }
+    (0..usize::BITS as usize)
+        .filter(|bit| mask & (1 << bit) != 0)
+        .all(|bit| {
+            base.checked_add(bit)
+                .is_some_and(|hart_id| index_of(hart_id).is_some())
+        })
+}
+
+/// Index of current hart as `index_of` does, or `usize::MAX` if the hart is not usable.
+///
+/// Called from `entry` before current hart has a stack; only `a0` and `t0` to `t3` are written.
+#[naked]
+pub unsafe extern "C" fn current_index_without_stack() -> usize {
+    core::arch::asm!(
+        "   csrr    a0, mhartid
+            la      t0, {loaded}
+            lbu     t0, 0(t0)
+            bnez    t0, 1f
+            li      t0, {num_hart_max}
+            bltu    a0, t0, 3f
+            j       2f",
+        // search the loaded hart list
+        "1: la      t0, {hart_ids}
+            la      t1, {num_harts}
+            ld      t1, 0(t1)
+            li      t2, 0
+        4:  beq     t2, t1, 2f
+            ld      t3, 0(t0)
+            beq     t3, a0, 5f
+            addi    t0, t0, 8
+            addi    t2, t2, 1
+            j       4b
+        5:  mv      a0, t2
+            ret",
+        "2: li      a0, -1",
+        "3: ret",
+        loaded = sym HART_LIST_LOADED,
+        hart_ids = sym HART_IDS,
+        num_harts = sym NUM_HARTS,
+        num_hart_max = const NUM_HART_MAX,
+        options(noreturn)
+    )
+}
+
+/// IDs of all usable harts in order of their index.
+pub fn hart_ids() -> impl Iterator<Item = usize> {
+    let loaded = HART_LIST_LOADED.load(Ordering::Acquire);
+    let count = if loaded {
+        NUM_HARTS.load(Ordering::Relaxed)
+    } else {
+        NUM_HART_MAX
+    };
+    (0..count).map(move |index| {
+        if loaded {
+            HART_IDS[index].load(Ordering::Relaxed)
+        } else {
+            index
+        }
+    })
+}
+
+/// Entry of a usable hart in a per-hart table indexed by hart index.
+#[inline]
+pub fn entry_of<T>(table: &[T], hart_id: usize) -> Option<&T> {
+    table.get(index_of(hart_id)?)
+}
+
+/// Halts a hart that is not usable, keeping it away from firmware and supervisor memory.
+pub fn halt(hart_id: usize) -> ! {
+    info!("hart {} is not usable, halted", hart_id);
+    unsafe { mie::clear_msoft() };
+    loop {
+        riscv::asm::wfi();
+    }
+}
+
+/// Configures trap delegation, counter access and `menvcfg` of current hart.
+pub fn init(hart_id: usize) {
+    unsafe {
+        medeleg::set_instruction_misaligned();
+        medeleg::set_instruction_fault();
+        // e.g. lazy FPU and vector enabling, and signals of user programs
+        medeleg::set_illegal_instruction();
+        // supervisor software emulates misaligned accesses it makes
+        medeleg::set_load_misaligned();
+        medeleg::set_store_misaligned();
+        medeleg::set_load_fault();
+        medeleg::set_store_fault();
+        medeleg::set_breakpoint();
+        medeleg::set_user_env_call();
+        medeleg::set_instruction_page_fault();
+        medeleg::set_load_page_fault();
+        medeleg::set_store_page_fault();
+        mideleg::set_ssoft();
+        mideleg::set_stimer();
+        mideleg::set_sext();
+        mcounteren::set_cy();
+        mcounteren::set_tm();
+        mcounteren::set_ir();
+    }
+    if has_hypervisor() {
+        unsafe {
+            core::arch::asm!(
+                "csrs medeleg, {}",
+                "csrs mideleg, {}",
+                in(reg) MEDELEG_HYPERVISOR,
+                in(reg) MIDELEG_HYPERVISOR,
+            )
+        };
+    }
+    let sstc = unsafe { set_menvcfg_or_fault(MENVCFG_STCE) } & MENVCFG_STCE != 0;
+    if sstc {
+        trace!(
+            "hart {} supports Sstc, supervisor timer compare enabled",
+            hart_id
+        );
+    }
+    if let Some(slot) = entry_of(&SSTC, hart_id) {
+        slot.store(sstc, Ordering::Release);
+    }
+}
+
+/// Whether the hart has Sstc enabled, i.e. supervisor timer is set through `stimecmp`.
+#[inline]
+pub fn has_sstc(hart_id: usize) -> bool {
+    entry_of(&SSTC, hart_id).is_some_and(|sstc| sstc.load(Ordering::Acquire))
+}
+
+/// Whether current hart implements the H extension.
+#[inline]
+pub fn has_hypervisor() -> bool {
+    misa::read().is_some_and(|misa| misa.has_extension('H'))
+}
+
+/// Sets `bits` in `menvcfg` and returns its new value, or 0 if `menvcfg` is not implemented.
+#[naked]
+unsafe extern "C" fn set_menvcfg_or_fault(bits: usize) -> usize {
+    core::arch::asm!(
+        // temporarily redirect machine traps to label 2
+        "   csrr    t1, mtvec
+            la      t0, 2f
+            csrw    mtvec, t0
+            csrs    0x30a, a0
+            csrr    a0, 0x30a
+            j       3f",
+        ".align 2",
+        "2: li      a0, 0",
+        "3: csrw    mtvec, t1
+            ret",
+        options(noreturn)
+    )
+}
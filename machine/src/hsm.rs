@@ -0,0 +1,200 @@
+//! Hart state management.
+//!
+//! Harts other than the boot hart wait in machine mode until started by `hart_start`;
+//! stopped and suspended harts sleep in `wfi` and are woken by CLINT software interrupts.
+
+use crate::{NextStage, NUM_HART_MAX};
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "hsm")]
+use riscv::register::mhartid;
+use riscv::register::{mie, mip, mstatus::MPP};
+use rustsbi::spec::hsm::hart_state;
+#[cfg(feature = "hsm")]
+use rustsbi::{spec::hsm::suspend_type, SbiRet};
+use spin::Mutex;
+
+/// Start address and opaque value requested by `hart_start`.
+#[derive(Clone, Copy)]
+pub struct HartStart {
+    pub start_addr: usize,
+    pub opaque: usize,
+}
+
+struct HartCell {
+    status: AtomicUsize,
+    start: Mutex<HartStart>,
+}
+
+impl HartCell {
+    const fn new() -> Self {
+        Self {
+            status: AtomicUsize::new(hart_state::STOPPED),
+            start: Mutex::new(HartStart {
+                start_addr: 0,
+                opaque: 0,
+            }),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const HART_CELL_INIT: HartCell = HartCell::new();
+static HARTS: [HartCell; NUM_HART_MAX] = [HART_CELL_INIT; NUM_HART_MAX];
+
+/// State of a usable hart; only usable harts run supervisor software and call HSM.
+#[inline]
+fn cell_of(hart_id: usize) -> &'static HartCell {
+    crate::hart::entry_of(&HARTS, hart_id).expect("hart is usable")
+}
+
+/// Marks the boot hart as started and all other harts as stopped.
+pub fn init(boot_hart_id: usize) {
+    for (hart_id, cell) in crate::hart::hart_ids().zip(&HARTS) {
+        let status = if hart_id == boot_hart_id {
+            hart_state::STARTED
+        } else {
+            hart_state::STOPPED
+        };
+        cell.status.store(status, Ordering::Release);
+    }
+}
+
+/// Whether the hart runs supervisor software, i.e. it is neither stopped nor being started.
+pub fn is_available(hart_id: usize) -> bool {
+    crate::hart::entry_of(&HARTS, hart_id).is_some_and(|cell| {
+        let status = cell.status.load(Ordering::Acquire);
+        status != hart_state::STOPPED && status != hart_state::START_PENDING
+    })
+}
+
+/// Whether all usable harts other than `hart_id` are stopped.
+#[cfg(feature = "susp")]
+pub fn others_stopped(hart_id: usize) -> bool {
+    crate::hart::hart_ids().zip(&HARTS).all(|(id, cell)| {
+        id == hart_id || cell.status.load(Ordering::Acquire) == hart_state::STOPPED
+    })
+}
+
+/// Waits in machine mode until current hart is started by `hart_start`.
+pub fn park(hart_id: usize) -> HartStart {
+    let cell = cell_of(hart_id);
+    // stopped harts must not be woken by a timer set before stopping
+    unsafe { mie::clear_mtimer() };
+    loop {
+        // supervisor IPIs are dropped while the hart is stopped; a remote fence
+        // may still be posted right before this hart stops
+        if crate::clint::take_ipi(hart_id) & crate::clint::IPI_FENCE != 0 {
+            crate::rfence::handle_pending(hart_id);
+        }
+        if cell.status.load(Ordering::Acquire) == hart_state::START_PENDING {
+            break;
+        }
+        wait_for_interrupt();
+    }
+    let start = *cell.start.lock();
+    unsafe {
+        mip::clear_ssoft();
+        mip::clear_stimer();
+    }
+    cell.status.store(hart_state::STARTED, Ordering::Release);
+    start
+}
+
+/// Sleeps until an interrupt is pending, waking on machine software interrupts as well.
+fn wait_for_interrupt() {
+    if crate::clint::is_present() {
+        unsafe {
+            mie::set_msoft();
+            riscv::asm::wfi();
+        }
+    } else {
+        core::hint::spin_loop()
+    }
+}
+
+/// SBI HSM extension backed by the per-hart state table.
+#[cfg(feature = "hsm")]
+pub struct MachineHsm;
+
+#[cfg(feature = "hsm")]
+impl rustsbi::Hsm for MachineHsm {
+    fn hart_start(&self, hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+        let Some(cell) = crate::hart::entry_of(&HARTS, hartid) else {
+            return SbiRet::invalid_param();
+        };
+        if !crate::memory::is_supervisor_memory(start_addr, 4) {
+            return SbiRet::invalid_address();
+        }
+        let mut start = cell.start.lock();
+        if cell.status.load(Ordering::Acquire) != hart_state::STOPPED {
+            return SbiRet::already_available();
+        }
+        *start = HartStart { start_addr, opaque };
+        cell.status
+            .store(hart_state::START_PENDING, Ordering::Release);
+        drop(start);
+        if !crate::clint::send_ipi(hartid, crate::clint::IPI_HSM) {
+            trace!(
+                "hart {} is started without CLINT, waking by polling",
+                hartid
+            );
+        }
+        SbiRet::success(0)
+    }
+
+    fn hart_stop(&self) -> SbiRet {
+        let hart_id = mhartid::read();
+        cell_of(hart_id)
+            .status
+            .store(hart_state::STOPPED, Ordering::Release);
+        let start = park(hart_id);
+        unsafe { enter_supervisor(hart_id, start) }
+    }
+
+    fn hart_get_status(&self, hartid: usize) -> SbiRet {
+        match crate::hart::entry_of(&HARTS, hartid) {
+            Some(cell) => SbiRet::success(cell.status.load(Ordering::Acquire)),
+            None => SbiRet::invalid_param(),
+        }
+    }
+
+    fn hart_suspend(&self, suspend_type: u32, resume_addr: usize, opaque: usize) -> SbiRet {
+        let retentive = match suspend_type {
+            suspend_type::RETENTIVE => true,
+            suspend_type::NON_RETENTIVE => false,
+            0x1000_0000..=0x7fff_ffff | 0x9000_0000..=0xffff_ffff => {
+                return SbiRet::not_supported()
+            }
+            _ => return SbiRet::invalid_param(),
+        };
+        if !retentive && !crate::memory::is_supervisor_memory(resume_addr, 4) {
+            return SbiRet::invalid_address();
+        }
+        let hart_id = mhartid::read();
+        let cell = cell_of(hart_id);
+        cell.status.store(hart_state::SUSPENDED, Ordering::Release);
+        // resume on any pending interrupt, including supervisor ones
+        wait_for_interrupt();
+        cell.status.store(hart_state::STARTED, Ordering::Release);
+        if retentive {
+            SbiRet::success(0)
+        } else {
+            let start = HartStart {
+                start_addr: resume_addr,
+                opaque,
+            };
+            unsafe { enter_supervisor(hart_id, start) }
+        }
+    }
+}
+
+/// Enters supervisor mode at `start.start_addr` with `satp` and `sstatus.SIE` cleared.
+pub unsafe fn enter_supervisor(hart_id: usize, start: HartStart) -> ! {
+    riscv::register::satp::set(riscv::register::satp::Mode::Bare, 0, 0);
+    riscv::register::sstatus::clear_sie();
+    let next_stage = NextStage {
+        start_addr: start.start_addr,
+        next_mode: MPP::Supervisor,
+    };
+    crate::enter_next_stage(hart_id, start.opaque, next_stage)
+}
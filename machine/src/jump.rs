@@ -0,0 +1,3 @@
+//! Next boot-loading stage fixed at build time.
+
+include!(concat!(env!("OUT_DIR"), "/jump.rs"));
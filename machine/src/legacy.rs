@@ -0,0 +1,121 @@
+//! Legacy SBI v0.1 calls.
+//!
+//! Console calls use the firmware console; other calls are forwarded to the extensions
+//! that replace them in the current SBI specification.
+
+use riscv::register::mip;
+use rustsbi::{
+    spec::{rfnc, spi, srst, time},
+    SbiRet,
+};
+
+const LEGACY_SET_TIMER: usize = 0;
+const LEGACY_CONSOLE_PUTCHAR: usize = 1;
+const LEGACY_CONSOLE_GETCHAR: usize = 2;
+const LEGACY_CLEAR_IPI: usize = 3;
+const LEGACY_SEND_IPI: usize = 4;
+const LEGACY_REMOTE_FENCE_I: usize = 5;
+const LEGACY_REMOTE_SFENCE_VMA: usize = 6;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: usize = 7;
+const LEGACY_SHUTDOWN: usize = 8;
+
+/// Handles a legacy call, returning the value of `a0`, or `None` if `extension` is not legacy.
+///
+/// Legacy calls return a single value and leave `a1` unchanged.
+pub fn handle_legacy(extension: usize, param: [usize; 6]) -> Option<usize> {
+    let [a0, a1, a2, a3, ..] = param;
+    let ans = match extension {
+        LEGACY_SET_TIMER => forward(time::EID_TIME, time::SET_TIMER, [a0, 0, 0, 0, 0, 0]),
+        LEGACY_CONSOLE_PUTCHAR => {
+            crate::console::putchar(a0 as u8);
+            return Some(0);
+        }
+        LEGACY_CONSOLE_GETCHAR => {
+            return Some(crate::console::getchar().map_or(usize::MAX, usize::from));
+        }
+        LEGACY_CLEAR_IPI => {
+            unsafe { mip::clear_ssoft() };
+            return Some(0);
+        }
+        LEGACY_SEND_IPI
+        | LEGACY_REMOTE_FENCE_I
+        | LEGACY_REMOTE_SFENCE_VMA
+        | LEGACY_REMOTE_SFENCE_VMA_ASID => {
+            let Some((mask, base)) = read_hart_mask(a0) else {
+                return Some(SbiRet::invalid_address().error);
+            };
+            let (extension, function, param) = match extension {
+                LEGACY_SEND_IPI => (spi::EID_SPI, spi::SEND_IPI, [mask, base, 0, 0, 0, 0]),
+                LEGACY_REMOTE_FENCE_I => {
+                    let param = [mask, base, 0, 0, 0, 0];
+                    (rfnc::EID_RFNC, rfnc::REMOTE_FENCE_I, param)
+                }
+                LEGACY_REMOTE_SFENCE_VMA => {
+                    let param = [mask, base, a1, a2, 0, 0];
+                    (rfnc::EID_RFNC, rfnc::REMOTE_SFENCE_VMA, param)
+                }
+                _ => {
+                    let param = [mask, base, a1, a2, a3, 0];
+                    (rfnc::EID_RFNC, rfnc::REMOTE_SFENCE_VMA_ASID, param)
+                }
+            };
+            forward(extension, function, param)
+        }
+        LEGACY_SHUTDOWN => {
+            let param = [
+                srst::RESET_TYPE_SHUTDOWN as usize,
+                srst::RESET_REASON_NO_REASON as usize,
+                0,
+                0,
+                0,
+                0,
+            ];
+            forward(srst::EID_SRST, srst::SYSTEM_RESET, param)
+        }
+        _ => return None,
+    };
+    Some(ans)
+}
+
+#[inline]
+fn forward(extension: usize, function: usize, param: [usize; 6]) -> usize {
+    crate::trap::handle_ecall(extension, function, param).error
+}
+
+/// Reads hart mask from supervisor virtual address `addr` as `(hart_mask, hart_mask_base)`.
+///
+/// A null address stands for all harts. Returns `None` if the mask cannot be read.
+fn read_hart_mask(addr: usize) -> Option<(usize, usize)> {
+    if addr == 0 {
+        return Some((0, usize::MAX));
+    }
+    let mut fault = 0;
+    let mask = unsafe { load_supervisor_usize(addr, &mut fault) };
+    (fault == 0).then_some((mask, 0))
+}
+
+/// Loads a `usize` from supervisor virtual address `addr` with `mstatus.MPRV` set.
+///
+/// Sets `fault` to 1 and returns 0 if the load traps.
+#[naked]
+unsafe extern "C" fn load_supervisor_usize(addr: usize, fault: &mut usize) -> usize {
+    core::arch::asm!(
+        // temporarily redirect machine traps to label 2, which restores `mstatus` below
+        "   csrr    t1, mtvec
+            csrr    t3, mstatus
+            la      t0, 2f
+            csrw    mtvec, t0
+            li      t2, 1 << 17
+            csrs    mstatus, t2
+            ld      a0, 0(a0)
+            j       3f",
+        ".align 2",
+        "2: li      t0, 1
+            sd      t0, 0(a1)
+            li      a0, 0",
+        "3: csrw    mstatus, t3
+            csrw    mtvec, t1
+            ret",
+        options(noreturn)
+    )
+}
@@ -19,3 +19,34 @@ macro_rules! println {
         drop(console);
     }}
 }
+
+/// Generates an accessor of CSRs `$base + index` by their runtime index.
+///
+/// CSR numbers are immediates in instructions, so one instruction is emitted per index.
+#[allow(unused)]
+macro_rules! csr_by_index {
+    (read $name:ident, $base:literal, $($index:literal)*) => {
+        #[inline]
+        fn $name(index: usize) -> usize {
+            let value: usize;
+            match index {
+                $($index => unsafe {
+                    core::arch::asm!("csrr {}, {csr}", out(reg) value, csr = const $base + $index)
+                },)*
+                _ => value = 0,
+            }
+            value
+        }
+    };
+    (write $name:ident, $base:literal, $($index:literal)*) => {
+        #[inline]
+        fn $name(index: usize, value: usize) {
+            match index {
+                $($index => unsafe {
+                    core::arch::asm!("csrw {csr}, {}", in(reg) value, csr = const $base + $index)
+                },)*
+                _ => {}
+            }
+        }
+    };
+}
@@ -7,119 +7,280 @@ extern crate log;
 #[macro_use]
 mod macros;
 
+mod clint;
 mod console;
+mod cppc;
 #[cfg(feature = "dynamic")]
 mod dynamic;
 #[cfg(feature = "fdt")]
 mod fdt;
+mod hart;
+mod hsm;
+#[cfg(all(feature = "jump", not(feature = "dynamic")))]
+mod jump;
+#[cfg(feature = "legacy")]
+mod legacy;
+mod memory;
+#[cfg(feature = "nacl")]
+mod nacl;
+#[cfg(all(feature = "payload", not(any(feature = "dynamic", feature = "jump"))))]
+mod payload;
+mod pmp;
+mod pmu;
 mod reset;
+mod rfence;
+mod sta;
+#[cfg(feature = "susp")]
+mod susp;
+mod trap;
 
 use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::{
+    mepc, mie, mscratch,
+    mstatus::{self, MPP},
+    mtvec::{self, TrapMode},
+};
 
 const LEN_STACK_PER_HART: usize = 16 * 1024;
+/// Maximum number of usable harts; their hart IDs may be sparse and exceed it.
 pub(crate) const NUM_HART_MAX: usize = 8;
-const LEN_STACK: usize = LEN_STACK_PER_HART * NUM_HART_MAX;
+/// Boot stack of the lottery winner, followed by the stack of each usable hart by hart index.
+const LEN_STACK: usize = LEN_STACK_PER_HART * (NUM_HART_MAX + 1);
 
+/// Number of harts that have entered the firmware; the first one becomes the boot hart.
+///
+/// Boot variables live in `.data.boot`, which is neither cleared as `.bss` nor copied as `.data`.
+#[link_section = ".data.boot"]
+static BOOT_LOTTERY: AtomicUsize = AtomicUsize::new(0);
+/// Holds non-boot harts without a stack until boot is finished.
+#[link_section = ".data.boot"]
 static BOOT_LOCK: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
 const UNINITIALIZED: usize = 0;
-const EARLY_BOOTING: usize = 1;
-const FINISHED: usize = 2;
+const FINISHED: usize = 1;
 
-#[cfg(feature = "dynamic")]
-static DYNAMIC_INFO: spin::RwLock<Option<dynamic::DynamicInfo>> = spin::RwLock::new(None);
-
-extern "C" fn main(hart_id: usize, opaque: usize, a2: usize) -> usize {
-    // TODO the hart clearing the '.bss' segment harts may enter this main function later,
-    // causing the variable 'BOOT_LOCK' cleared to zero after the `compare_exchange` here.
-    let old_boot_state = match BOOT_LOCK.compare_exchange(
-        UNINITIALIZED,
-        EARLY_BOOTING,
-        Ordering::SeqCst,
-        Ordering::SeqCst,
-    ) {
-        Ok(s) | Err(s) => s,
-    };
-
-    if old_boot_state == UNINITIALIZED {
+/// Next stage of the boot hart, which may differ from the hart winning the boot lottery.
+struct BootStage {
+    hart_id: usize,
+    opaque: usize,
+    next_stage: NextStage,
+}
+
+static BOOT_STAGE: spin::Once<BootStage> = spin::Once::new();
+
+/// Entry address and privilege mode of the next boot-loading stage.
+#[derive(Clone, Copy)]
+struct NextStage {
+    start_addr: usize,
+    next_mode: MPP,
+}
+
+/// Rust entry of all harts; `boot_hart` is set on the hart that won the boot lottery in `entry`.
+///
+/// The lottery winner prepares the firmware, then enters the next stage itself or hands
+/// it over to the boot hart preferred by dynamic information, or to the first usable hart
+/// if the winner is not usable.
+extern "C" fn main(hart_id: usize, opaque: usize, a2: usize, boot_hart: bool) -> ! {
+    unsafe { mtvec::write(trap::trap_entry as usize, TrapMode::Direct) };
+
+    if boot_hart {
         console::init();
 
-        trace!("hart {} obtained boot lock", hart_id);
+        trace!("hart {} won the boot lottery", hart_id);
         info!("Early console initialized using UART16550 @ 0x10000000");
 
         #[cfg(feature = "fdt")]
-        if let Ok(fdt) = fdt::try_read_fdt(opaque) {
+        let opaque = {
             let mut board = fdt::FdtBoard::new();
-            fdt::parse_fdt(fdt, &mut board);
+            let mut fdt_size = None;
+            if let Ok(fdt) = fdt::try_read_fdt(opaque) {
+                fdt_size = Some(fdt.total_size());
+                fdt::parse_fdt(fdt, &mut board);
+            }
             board.init();
-        }
-        #[cfg(not(feature = "fdt"))]
-        let _ = opaque;
+            fdt::BOARD.call_once(|| board);
+            // the next stage receives a patched copy of the device tree
+            match fdt_size.map(|size| fdt::patch_fdt(opaque, size)) {
+                Some(Ok(patched)) => patched,
+                Some(Err(e)) => {
+                    error!("patch device tree failed: {}", e);
+                    opaque
+                }
+                None => opaque,
+            }
+        };
+        info!("Firmware memory reserved at {:x?}", pmp::firmware_region());
 
         info!("RustSBI version {}", rustsbi::VERSION);
         for line in rustsbi::LOGO.lines() {
             info!("{}", line);
         }
         info!("Initializing RustSBI machine-mode environment.");
-        BOOT_LOCK.store(FINISHED, Ordering::SeqCst);
-    } else {
-        while BOOT_LOCK.load(Ordering::SeqCst) != FINISHED {
-            core::hint::spin_loop()
-        }
-    }
 
-    #[cfg(feature = "dynamic")]
-    if let Some(mut write) = DYNAMIC_INFO.try_write() {
-        trace!(
-            "hart {} is reading dynamic info from physical address 0x{:x}",
-            hart_id,
-            a2
-        );
-        if let Ok(info) = dynamic::try_read_dynamic(a2) {
-            trace!(
-                "dynamic info magic: {:x}, version: {}",
-                info.magic,
-                info.version
-            );
-            // TODO check magic and version
-            trace!(
-                "dynamic info would like to jump to address 0x{:x} with mode {}",
-                info.next_addr,
-                info.next_mode
+        // dynamic information may prefer another boot hart
+        let (next_stage, preferred_boot_hart): (NextStage, Option<usize>) = match () {
+            #[cfg(feature = "dynamic")]
+            () => {
+                trace!(
+                    "hart {} is reading dynamic info from physical address 0x{:x}",
+                    hart_id,
+                    a2
+                );
+                let info = match dynamic::try_read_dynamic(a2) {
+                    Ok(info) => info,
+                    Err(e) => {
+                        error!("read dynamic info failed: {}", e);
+                        reset::fail()
+                    }
+                };
+                trace!(
+                    "dynamic info magic: {:x}, version: {}",
+                    info.magic,
+                    info.version
+                );
+                trace!(
+                    "dynamic info would like to jump to address 0x{:x} with mode {}",
+                    info.next_addr,
+                    info.next_mode
+                );
+                // TODO options (we don't use it by now)
+                trace!("dynamic info has extra option: {:x}", info.options);
+                let Some(next_mode) = info.mpp() else {
+                    error!(
+                        "dynamic info has unsupported next mode {}, expected 0 (U), 1 (S) or 3 (M)",
+                        info.next_mode
+                    );
+                    reset::fail()
+                };
+                let next_stage = NextStage {
+                    start_addr: info.next_addr,
+                    next_mode,
+                };
+                let preferred =
+                    (info.version >= 2 && info.boot_hart != usize::MAX).then_some(info.boot_hart);
+                (next_stage, preferred)
+            }
+            #[cfg(all(feature = "jump", not(feature = "dynamic")))]
+            () => (
+                NextStage {
+                    start_addr: jump::JUMP_ADDRESS,
+                    next_mode: jump::JUMP_MODE,
+                },
+                None,
+            ),
+            #[cfg(all(feature = "payload", not(any(feature = "dynamic", feature = "jump"))))]
+            () => (
+                NextStage {
+                    start_addr: payload::payload_addr(),
+                    next_mode: MPP::Supervisor,
+                },
+                None,
+            ),
+            #[cfg(not(any(feature = "dynamic", feature = "jump", feature = "payload")))]
+            () => {
+                error!("no next stage configured; enable `dynamic`, `jump` or `payload` feature");
+                reset::fail()
+            }
+        };
+        // an unusable lottery winner hands boot over to the first usable hart
+        let fallback_boot_hart = if hart::index_of(hart_id).is_some() {
+            hart_id
+        } else if let Some(first) = hart::hart_ids().next() {
+            warn!(
+                "hart {} is not listed as usable in device tree, booting on hart {}",
+                hart_id, first
             );
-            // TODO options (we don't use it by now)
-            trace!("dynamic info has extra option: {:x}", info.options);
-            info!("Redirecting harts to address 0x{:x}", info.next_addr);
-            *write = Some(info);
+            first
         } else {
-            error!("read dynamic info failed");
+            error!("no usable hart to boot on");
             reset::fail()
-        }
-    }
-    #[cfg(not(feature = "dynamic"))]
-    let _ = a2;
-
-    match () {
-        #[cfg(feature = "dynamic")]
-        () => loop {
-            if let Some(info) = *DYNAMIC_INFO.read() {
-                return info.next_addr;
+        };
+        let boot_hart_id = match preferred_boot_hart {
+            Some(preferred) if hart::index_of(preferred).is_some() => preferred,
+            Some(preferred) => {
+                warn!(
+                    "preferred boot hart {} is not usable, booting on hart {}",
+                    preferred, fallback_boot_hart
+                );
+                fallback_boot_hart
             }
-            core::hint::spin_loop()
-        },
-        // TODO non-dynamic supervisor address
+            None => fallback_boot_hart,
+        };
+        hsm::init(boot_hart_id);
+        record_stack_top(hart_id);
+        info!(
+            "Redirecting hart {} to address 0x{:x} in {:?} mode",
+            boot_hart_id, next_stage.start_addr, next_stage.next_mode
+        );
+        BOOT_STAGE.call_once(|| BootStage {
+            hart_id: boot_hart_id,
+            opaque,
+            next_stage,
+        });
+        BOOT_LOCK.store(FINISHED, Ordering::SeqCst);
         #[cfg(not(feature = "dynamic"))]
-        () => {
-            static NON_DYNAMIC_LOG_ONCE: spin::Once<()> = spin::Once::new();
-            NON_DYNAMIC_LOG_ONCE.call_once(|| {
-                error!("non-dynamic jump address is not yet supported");
-                reset::fail()
-            });
-            trace!("wait before shutdown");
-            loop {
-                core::hint::spin_loop()
-            }
+        let _ = a2;
+        if boot_hart_id == hart_id {
+            unsafe { enter_next_stage(hart_id, opaque, next_stage) }
+        }
+        info!("Hart {} hands boot over to hart {}", hart_id, boot_hart_id);
+    } else {
+        // `entry` held this hart until boot finished
+        record_stack_top(hart_id);
+        if let Some(boot) = BOOT_STAGE.get().filter(|boot| boot.hart_id == hart_id) {
+            unsafe { enter_next_stage(hart_id, boot.opaque, boot.next_stage) }
         }
     }
+    if hart::index_of(hart_id).is_none() {
+        hart::halt(hart_id)
+    }
+    // other harts wait in machine mode until started through SBI HSM
+    let start = hsm::park(hart_id);
+    unsafe { hsm::enter_supervisor(hart_id, start) }
+}
+
+/// Drop into the next boot-loading stage with hart ID in `a0` and `opaque` in `a1`.
+#[inline]
+unsafe fn enter_next_stage(hart_id: usize, opaque: usize, next_stage: NextStage) -> ! {
+    // traps from the next stage start on an empty machine-mode stack
+    if let Some(stack_top) = stack_top(hart_id) {
+        mscratch::write(stack_top);
+    }
+    hart::init(hart_id);
+    pmp::init(hart_id);
+    pmu::init(hart_id);
+    // remote fence requests from other harts arrive as machine software interrupts
+    if clint::is_present() {
+        mie::set_msoft();
+    }
+    mstatus::set_mpp(next_stage.next_mode);
+    mepc::write(next_stage.start_addr);
+    core::arch::asm!(
+        "mret",
+        in("a0") hart_id,
+        in("a1") opaque,
+        options(noreturn)
+    )
+}
+
+/// Top of machine-mode stack of each usable hart by hart index.
+///
+/// The lottery winner keeps the boot stack; other harts take the stack of their index in `entry`.
+#[allow(clippy::declare_interior_mutable_const)]
+const STACK_TOP_INIT: AtomicUsize = AtomicUsize::new(0);
+static STACK_TOPS: [AtomicUsize; NUM_HART_MAX] = [STACK_TOP_INIT; NUM_HART_MAX];
+
+/// Keeps the stack top of current hart, still in `mscratch` as set by `entry`.
+#[inline]
+fn record_stack_top(hart_id: usize) {
+    if let Some(slot) = hart::entry_of(&STACK_TOPS, hart_id) {
+        slot.store(mscratch::read(), Ordering::Relaxed);
+    }
+}
+
+/// Top of the machine-mode stack of a usable hart.
+#[inline]
+fn stack_top(hart_id: usize) -> Option<usize> {
+    hart::entry_of(&STACK_TOPS, hart_id).map(|slot| slot.load(Ordering::Relaxed))
 }
 
 // TODO contribute `Stack` struct into the crate `riscv`
@@ -137,16 +298,23 @@ unsafe extern "C" fn entry() -> ! {
         // 1. Turn off interrupt
         "   csrw    mie, zero",
         // 2. Initialize programming langauge runtime
-        // only clear bss if hartid is zero
-        "   csrr    t0, mhartid",
-        "   bnez    t0, 2f",
+        // the first hart drawing the boot lottery clears bss and prepares data,
+        // whatever its hart ID is; other harts wait without a stack until boot finishes
+        "   la      t0, {boot_lottery}
+            li      t1, 1
+            .option push
+            .option arch, +a
+            amoadd.d t1, t1, (t0)
+            .option pop
+            bnez    t1, 4f",
         // clear bss segment
         "   la      t0, sbss
             la      t1, ebss
         1:  bgeu    t0, t1, 2f
             sd      zero, 0(t0)
             addi    t0, t0, 8
-            j       1b",
+            j       1b
+        2: ",
         // prepare data segment
         "   la      t3, sidata
             la      t4, sdata
@@ -156,22 +324,44 @@ unsafe extern "C" fn entry() -> ! {
             sd      t6, 0(t4)
             addi    t3, t3, 8
             addi    t4, t4, 8
-            j       1b",
-        "2: ",
-        // 3. Prepare stack for each hart
+            j       1b
+        2: ",
+        // mark current hart as boot hart, running on the first stack
+        "   li      a3, 1
+            li      t1, 1
+            j       5f",
+        "4: la      t0, {boot_lock}
+            li      t2, {finished}
+        1:  ld      t1, 0(t0)
+            bne     t1, t2, 1b
+            fence   rw, rw",
+        // usable harts take the stack after the boot stack by their index, others sleep forever
+        "   mv      s0, a0
+            call    {current_index}
+            li      t0, -1
+            bne     a0, t0, 2f
+        1:  wfi
+            j       1b
+        2:  addi    t1, a0, 2
+            mv      a0, s0
+            li      a3, 0",
+        "5: ",
+        // 3. Prepare stack, the `t1`-th one
         "   la      sp, {stack}",
         "   li      t0, {per_hart_stack_size}",
-        "   csrr    t1, mhartid",
-        "   addi    t1, t1, 1",
         "1: ",
         "   add     sp, sp, t0",
         "   addi    t1, t1, -1",
         "   bnez    t1, 1b",
+        // machine-mode trap entry switches to this stack using mscratch
+        "   csrw    mscratch, sp",
         // 4. Run Rust main function
         "   j       {main}",
-        // 5. Jump to following boot sequences
-        "   jr      a0", // TODO
         per_hart_stack_size = const LEN_STACK_PER_HART,
+        boot_lottery = sym BOOT_LOTTERY,
+        boot_lock = sym BOOT_LOCK,
+        finished = const FINISHED,
+        current_index = sym hart::current_index_without_stack,
         stack = sym STACK,
         main = sym main,
         options(noreturn)
@@ -0,0 +1,118 @@
+//! Physical memory map for validating addresses passed by supervisor software.
+
+use core::ops::Range;
+
+/// Maximum number of memory or reserved regions kept in the map.
+const NUM_REGIONS_MAX: usize = 8;
+
+/// System memory and regions reserved from it.
+#[derive(Default)]
+pub struct MemoryMap {
+    memory: [Range<usize>; NUM_REGIONS_MAX],
+    num_memory: usize,
+    reserved: [Range<usize>; NUM_REGIONS_MAX],
+    num_reserved: usize,
+}
+
+impl MemoryMap {
+    /// Adds a region of system memory, returning `false` if the map is full.
+    #[inline]
+    pub fn add_memory(&mut self, range: Range<usize>) -> bool {
+        push(&mut self.memory, &mut self.num_memory, range)
+    }
+
+    /// Adds a region not usable by supervisor software, returning `false` if the map is full.
+    #[inline]
+    pub fn add_reserved(&mut self, range: Range<usize>) -> bool {
+        push(&mut self.reserved, &mut self.num_reserved, range)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.num_memory == 0
+    }
+
+    /// Whether `range` lies within one memory region, reserved or not.
+    fn in_memory(&self, range: &Range<usize>) -> bool {
+        self.memory[..self.num_memory]
+            .iter()
+            .any(|memory| memory.start <= range.start && range.end <= memory.end)
+    }
+
+    /// Whether `range` lies within one memory region and outside all reserved regions.
+    fn contains(&self, range: &Range<usize>) -> bool {
+        self.in_memory(range)
+            && !self.reserved[..self.num_reserved]
+                .iter()
+                .any(|reserved| overlaps(reserved, range))
+    }
+}
+
+impl core::fmt::Debug for MemoryMap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MemoryMap")
+            .field("memory", &&self.memory[..self.num_memory])
+            .field("reserved", &&self.reserved[..self.num_reserved])
+            .finish()
+    }
+}
+
+static MEMORY_MAP: spin::Once<MemoryMap> = spin::Once::new();
+
+#[cfg(feature = "fdt")]
+pub fn load_memory_map(map: MemoryMap) {
+    MEMORY_MAP.call_once(|| map);
+}
+
+/// Whether supervisor software owns the `len` bytes at physical address `start`.
+///
+/// The range must be in system memory and out of firmware and reserved regions.
+/// Before a memory map is loaded only firmware memory is rejected.
+pub fn is_supervisor_memory(start: usize, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(end) = start.checked_add(len) else {
+        return false;
+    };
+    let range = start..end;
+    if overlaps(&crate::pmp::firmware_region(), &range) {
+        return false;
+    }
+    match MEMORY_MAP.get() {
+        Some(map) => map.contains(&range),
+        None => true,
+    }
+}
+
+/// Whether the `len` bytes at physical address `start` are system memory, e.g. for
+/// information passed by the previous boot stage, which may be in reserved regions.
+///
+/// Before a memory map is loaded all ranges are accepted.
+#[cfg(feature = "dynamic")]
+pub fn is_system_memory(start: usize, len: usize) -> bool {
+    let Some(end) = start.checked_add(len) else {
+        return false;
+    };
+    match MEMORY_MAP.get() {
+        Some(map) => map.in_memory(&(start..end)),
+        None => true,
+    }
+}
+
+#[inline]
+fn push(regions: &mut [Range<usize>], len: &mut usize, range: Range<usize>) -> bool {
+    match regions.get_mut(*len) {
+        Some(slot) => {
+            *slot = range;
+            *len += 1;
+            true
+        }
+        None => false,
+    }
+}
+
+#[inline]
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
@@ -0,0 +1,382 @@
+//! Nested acceleration through per-hart shared memory on harts with the H extension.
+//!
+//! Supervisor software, i.e. a hypervisor, batches H-extension CSR writes and HFENCE
+//! requests in shared memory; synchronizing applies them to hardware CSRs and executes
+//! the fences on the calling hart, and synchronized SRET also returns into the guest.
+
+use crate::NUM_HART_MAX;
+use core::{
+    arch::asm,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use riscv::register::{
+    mepc, mhartid, mscratch,
+    mstatus::{self, MPP},
+    sepc,
+    sstatus::{self, SPP},
+};
+use rustsbi::{
+    spec::{
+        binary::SharedPtr,
+        nacl::{feature_id, shmem_size::NATIVE},
+    },
+    SbiRet,
+};
+
+const XLEN_BYTES: usize = core::mem::size_of::<usize>();
+/// Shared memory layout: scratch space areas, then one word per CSR.
+const SRET_OFFSET: usize = 0x0000;
+const AUTOSWAP_OFFSET: usize = 0x0200;
+const HFENCE_OFFSET: usize = 0x0800;
+const DIRTY_BITMAP_OFFSET: usize = 0x0f80;
+const CSR_OFFSET: usize = 0x1000;
+const SHMEM_ALIGN: usize = 4096;
+/// Value of per-hart shared memory address when it is disabled, same as in `set_shmem`.
+const NO_SHMEM: usize = usize::MAX;
+
+/// Autoswap flag of `hstatus`, followed in the autoswap area by the value to swap.
+const AUTOSWAP_HSTATUS: usize = 1 << 0;
+
+/// Nested HFENCE entries of config, page number, page count and a reserved word.
+const NUM_HFENCE_ENTRIES: usize = 3840 / usize::BITS as usize;
+const HFENCE_ENTRY_SIZE: usize = 4 * XLEN_BYTES;
+const HFENCE_PEND: usize = 1 << (usize::BITS - 1);
+const HFENCE_TYPE_SHIFT: u32 = usize::BITS - 8;
+const HFENCE_ORDER_SHIFT: u32 = usize::BITS - 16;
+const HFENCE_ORDER_BASE: usize = 12;
+const HFENCE_VMID_SHIFT: u32 = 16;
+const HFENCE_GVMA: usize = 0;
+const HFENCE_GVMA_ALL: usize = 1;
+const HFENCE_GVMA_VMID: usize = 2;
+const HFENCE_GVMA_VMID_ALL: usize = 3;
+const HFENCE_VVMA: usize = 4;
+const HFENCE_VVMA_ALL: usize = 5;
+const HFENCE_VVMA_ASID: usize = 6;
+const HFENCE_VVMA_ASID_ALL: usize = 7;
+/// Flush the whole address space instead of page by page above this number of pages.
+const FULL_FLUSH_PAGES: usize = 64;
+
+const HSTATUS: usize = 0x600;
+const HSTATUS_SPV: usize = 1 << 7;
+const MSTATUS_MPV: usize = 1 << 39;
+
+/// H-extension and VS CSRs synchronized through shared memory.
+const H_CSRS: &[usize] = &[
+    0x200, 0x204, 0x205, 0x240, 0x241, 0x242, 0x243, 0x244, 0x280, // VS CSRs
+    0x600, 0x602, 0x603, 0x604, 0x605, 0x606, 0x607, 0x643, 0x644, 0x645, 0x64a,
+    0x680, // HS CSRs
+    0xe12, // `hgeip`
+];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SHMEM_INIT: AtomicUsize = AtomicUsize::new(NO_SHMEM);
+static SHMEM: [AtomicUsize; NUM_HART_MAX] = [SHMEM_INIT; NUM_HART_MAX];
+
+/// Nested acceleration shared memory of one hart, at a physical address in supervisor memory.
+#[derive(Clone, Copy)]
+struct Shmem(usize);
+
+impl Shmem {
+    #[inline]
+    fn of_hart(hart_id: usize) -> Option<Self> {
+        match crate::hart::entry_of(&SHMEM, hart_id)?.load(Ordering::Acquire) {
+            NO_SHMEM => None,
+            addr => Some(Self(addr)),
+        }
+    }
+
+    #[inline]
+    fn read(self, offset: usize) -> usize {
+        unsafe { ptr::read_volatile((self.0 + offset) as *const usize) }
+    }
+
+    #[inline]
+    fn write(self, offset: usize, value: usize) {
+        unsafe { ptr::write_volatile((self.0 + offset) as *mut usize, value) }
+    }
+
+    /// Clears the dirty bit of `csr`, returning whether it was set.
+    #[inline]
+    fn take_dirty(self, csr: usize) -> bool {
+        let index = csr_index(csr);
+        let offset = DIRTY_BITMAP_OFFSET + index / usize::BITS as usize * XLEN_BYTES;
+        let bit = 1 << (index % usize::BITS as usize);
+        let word = self.read(offset);
+        if word & bit != 0 {
+            self.write(offset, word & !bit);
+        }
+        word & bit != 0
+    }
+
+    /// Writes `csr` from shared memory if dirty, then reads its value back into shared memory.
+    fn sync_csr(self, csr: usize) {
+        let offset = CSR_OFFSET + csr_index(csr) * XLEN_BYTES;
+        if self.take_dirty(csr) {
+            write_h_csr(csr, self.read(offset));
+        }
+        self.write(offset, read_h_csr(csr));
+    }
+
+    /// Executes the HFENCE entry if pending, then marks it as done.
+    fn sync_hfence(self, entry_index: usize) {
+        let offset = HFENCE_OFFSET + entry_index * HFENCE_ENTRY_SIZE;
+        let config = self.read(offset);
+        if config & HFENCE_PEND == 0 {
+            return;
+        }
+        let pnum = self.read(offset + XLEN_BYTES);
+        let pcount = self.read(offset + 2 * XLEN_BYTES);
+        execute_hfence(config, pnum, pcount);
+        self.write(offset, config & !HFENCE_PEND);
+    }
+
+    fn sync_all(self) {
+        for &csr in H_CSRS {
+            self.sync_csr(csr);
+        }
+        for entry_index in 0..NUM_HFENCE_ENTRIES {
+            self.sync_hfence(entry_index);
+        }
+    }
+}
+
+/// Index of `csr` in the CSR area of shared memory.
+#[inline]
+fn csr_index(csr: usize) -> usize {
+    ((csr & 0xc00) >> 2) | (csr & 0xff)
+}
+
+fn execute_hfence(config: usize, pnum: usize, pcount: usize) {
+    let kind = (config >> HFENCE_TYPE_SHIFT) & 0xf;
+    let order = ((config >> HFENCE_ORDER_SHIFT) & 0x7f) + HFENCE_ORDER_BASE;
+    let vmid = (config >> HFENCE_VMID_SHIFT) & 0x3fff;
+    let asid = config & 0xffff;
+    let each_page = |f: &mut dyn FnMut(Option<usize>)| {
+        if pcount > FULL_FLUSH_PAGES || order >= usize::BITS as usize {
+            return f(None);
+        }
+        for page in pnum..pnum.saturating_add(pcount) {
+            f(Some(page << order));
+        }
+    };
+    unsafe {
+        match kind {
+            // hfence.gvma rs1, rs2
+            HFENCE_GVMA => each_page(&mut |addr| match addr {
+                Some(addr) => asm!(".insn r 0x73, 0, 0x31, x0, {}, x0", in(reg) addr >> 2),
+                None => asm!(".insn r 0x73, 0, 0x31, x0, x0, x0"),
+            }),
+            HFENCE_GVMA_ALL => asm!(".insn r 0x73, 0, 0x31, x0, x0, x0"),
+            HFENCE_GVMA_VMID => each_page(&mut |addr| match addr {
+                Some(addr) => {
+                    asm!(".insn r 0x73, 0, 0x31, x0, {}, {}", in(reg) addr >> 2, in(reg) vmid)
+                }
+                None => asm!(".insn r 0x73, 0, 0x31, x0, x0, {}", in(reg) vmid),
+            }),
+            HFENCE_GVMA_VMID_ALL => asm!(".insn r 0x73, 0, 0x31, x0, x0, {}", in(reg) vmid),
+            // hfence.vvma rs1, rs2 applies to the VMID in `hgatp`
+            HFENCE_VVMA..=HFENCE_VVMA_ASID_ALL => crate::rfence::with_vmid(vmid, || match kind {
+                HFENCE_VVMA => each_page(&mut |addr| match addr {
+                    Some(addr) => asm!(".insn r 0x73, 0, 0x11, x0, {}, x0", in(reg) addr),
+                    None => asm!(".insn r 0x73, 0, 0x11, x0, x0, x0"),
+                }),
+                HFENCE_VVMA_ALL => asm!(".insn r 0x73, 0, 0x11, x0, x0, x0"),
+                HFENCE_VVMA_ASID => each_page(&mut |addr| match addr {
+                    Some(addr) => {
+                        asm!(".insn r 0x73, 0, 0x11, x0, {}, {}", in(reg) addr, in(reg) asid)
+                    }
+                    None => asm!(".insn r 0x73, 0, 0x11, x0, x0, {}", in(reg) asid),
+                }),
+                _ => asm!(".insn r 0x73, 0, 0x11, x0, x0, {}", in(reg) asid),
+            }),
+            _ => warn!("unknown nested HFENCE type {}", kind),
+        }
+    }
+}
+
+/// Emulates SRET of supervisor software, then returns with its registers from shared memory.
+unsafe fn sret(hart_id: usize, shmem: Shmem) -> ! {
+    let hstatus = read_h_csr(HSTATUS);
+    let sstatus = sstatus::read();
+    mepc::write(sepc::read());
+    mstatus::set_mpp(match sstatus.spp() {
+        SPP::Supervisor => MPP::Supervisor,
+        SPP::User => MPP::User,
+    });
+    if hstatus & HSTATUS_SPV != 0 {
+        asm!("csrs mstatus, {}", in(reg) MSTATUS_MPV);
+    } else {
+        asm!("csrc mstatus, {}", in(reg) MSTATUS_MPV);
+    }
+    if sstatus.spie() {
+        sstatus::set_sie();
+    } else {
+        sstatus::clear_sie();
+    }
+    sstatus::set_spie();
+    sstatus::set_spp(SPP::User);
+    write_h_csr(HSTATUS, hstatus & !HSTATUS_SPV);
+    // leaving through `mret` instead of trap return, so reset to an empty machine-mode stack
+    if let Some(stack_top) = crate::stack_top(hart_id) {
+        mscratch::write(stack_top);
+    }
+    restore_and_mret(shmem.0 + SRET_OFFSET)
+}
+
+/// Loads `x1` to `x31` from the SRET area, in which `x<i>` is at word `i`, and returns.
+#[naked]
+unsafe extern "C" fn restore_and_mret(gprs: usize) -> ! {
+    asm!(
+        "   ld      x1, 1*8(a0)
+            ld      x2, 2*8(a0)
+            ld      x3, 3*8(a0)
+            ld      x4, 4*8(a0)
+            ld      x5, 5*8(a0)
+            ld      x6, 6*8(a0)
+            ld      x7, 7*8(a0)
+            ld      x8, 8*8(a0)
+            ld      x9, 9*8(a0)
+            ld      x11, 11*8(a0)
+            ld      x12, 12*8(a0)
+            ld      x13, 13*8(a0)
+            ld      x14, 14*8(a0)
+            ld      x15, 15*8(a0)
+            ld      x16, 16*8(a0)
+            ld      x17, 17*8(a0)
+            ld      x18, 18*8(a0)
+            ld      x19, 19*8(a0)
+            ld      x20, 20*8(a0)
+            ld      x21, 21*8(a0)
+            ld      x22, 22*8(a0)
+            ld      x23, 23*8(a0)
+            ld      x24, 24*8(a0)
+            ld      x25, 25*8(a0)
+            ld      x26, 26*8(a0)
+            ld      x27, 27*8(a0)
+            ld      x28, 28*8(a0)
+            ld      x29, 29*8(a0)
+            ld      x30, 30*8(a0)
+            ld      x31, 31*8(a0)
+            ld      x10, 10*8(a0)
+            mret",
+        options(noreturn)
+    )
+}
+
+/// SBI NACL extension; functions return `not_supported` on harts without the H extension.
+pub struct MachineNacl;
+
+impl MachineNacl {
+    /// Shared memory of current hart, or the error of calling a sync function without it.
+    #[inline]
+    fn shmem(&self) -> Result<(usize, Shmem), SbiRet> {
+        if !crate::hart::has_hypervisor() {
+            return Err(SbiRet::not_supported());
+        }
+        let hart_id = mhartid::read();
+        match Shmem::of_hart(hart_id) {
+            Some(shmem) => Ok((hart_id, shmem)),
+            None => Err(SbiRet::no_shmem()),
+        }
+    }
+}
+
+impl rustsbi::Nacl for MachineNacl {
+    fn probe_feature(&self, feature_id: u32) -> SbiRet {
+        let available = crate::hart::has_hypervisor()
+            && matches!(
+                feature_id as usize,
+                feature_id::SYNC_CSR
+                    | feature_id::SYNC_HFENCE
+                    | feature_id::SYNC_SRET
+                    | feature_id::AUTOSWAP_CSR
+            );
+        SbiRet::success(available as usize)
+    }
+
+    fn set_shmem(&self, shmem: SharedPtr<[u8; NATIVE]>, flags: usize) -> SbiRet {
+        if !crate::hart::has_hypervisor() {
+            return SbiRet::not_supported();
+        }
+        let hart_id = mhartid::read();
+        let Some(slot) = crate::hart::entry_of(&SHMEM, hart_id) else {
+            return SbiRet::failed();
+        };
+        let (lo, hi) = (shmem.phys_addr_lo(), shmem.phys_addr_hi());
+        if flags != 0 {
+            return SbiRet::invalid_param();
+        }
+        if lo == usize::MAX && hi == usize::MAX {
+            slot.store(NO_SHMEM, Ordering::Release);
+            return SbiRet::success(0);
+        }
+        if lo % SHMEM_ALIGN != 0 {
+            return SbiRet::invalid_param();
+        }
+        if hi != 0 || !crate::memory::is_supervisor_memory(lo, NATIVE) {
+            return SbiRet::invalid_address();
+        }
+        unsafe { ptr::write_bytes(lo as *mut u8, 0, NATIVE) };
+        slot.store(lo, Ordering::Release);
+        trace!(
+            "hart {} nested acceleration shared memory at 0x{:x}",
+            hart_id,
+            lo
+        );
+        SbiRet::success(0)
+    }
+
+    fn sync_csr(&self, csr_num: usize) -> SbiRet {
+        let shmem = match self.shmem() {
+            Ok((_, shmem)) => shmem,
+            Err(ans) => return ans,
+        };
+        if csr_num == usize::MAX {
+            for &csr in H_CSRS {
+                shmem.sync_csr(csr);
+            }
+        } else if H_CSRS.contains(&csr_num) {
+            shmem.sync_csr(csr_num);
+        } else {
+            return SbiRet::invalid_param();
+        }
+        SbiRet::success(0)
+    }
+
+    fn sync_hfence(&self, entry_index: usize) -> SbiRet {
+        let shmem = match self.shmem() {
+            Ok((_, shmem)) => shmem,
+            Err(ans) => return ans,
+        };
+        if entry_index == usize::MAX {
+            for entry_index in 0..NUM_HFENCE_ENTRIES {
+                shmem.sync_hfence(entry_index);
+            }
+        } else if entry_index < NUM_HFENCE_ENTRIES {
+            shmem.sync_hfence(entry_index);
+        } else {
+            return SbiRet::invalid_param();
+        }
+        SbiRet::success(0)
+    }
+
+    fn sync_sret(&self) -> SbiRet {
+        let (hart_id, shmem) = match self.shmem() {
+            Ok(current) => current,
+            Err(ans) => return ans,
+        };
+        shmem.sync_all();
+        if shmem.read(AUTOSWAP_OFFSET) & AUTOSWAP_HSTATUS != 0 {
+            let offset = AUTOSWAP_OFFSET + XLEN_BYTES;
+            let hstatus = read_h_csr(HSTATUS);
+            write_h_csr(HSTATUS, shmem.read(offset));
+            shmem.write(offset, hstatus);
+        }
+        unsafe { sret(hart_id, shmem) }
+    }
+}
+
+csr_by_index!(read read_h_csr, 0, 0x200 0x204 0x205 0x240 0x241 0x242 0x243 0x244 0x280 0x600 0x602 0x603 0x604 0x605 0x606 0x607 0x643 0x644 0x645 0x64a 0x680 0xe12);
+// all of `H_CSRS` but read-only `hgeip`
+csr_by_index!(write write_h_csr, 0, 0x200 0x204 0x205 0x240 0x241 0x242 0x243 0x244 0x280 0x600 0x602 0x603 0x604 0x605 0x606 0x607 0x643 0x644 0x645 0x64a 0x680);
@@ -0,0 +1,12 @@
+//! Next boot-loading stage image embedded into this firmware.
+
+const LEN_PAYLOAD: usize = include_bytes!(env!("RUSTSBI_PAYLOAD")).len();
+
+#[link_section = ".payload"]
+static PAYLOAD: [u8; LEN_PAYLOAD] = *include_bytes!(env!("RUSTSBI_PAYLOAD"));
+
+/// Address of the embedded next stage image.
+#[inline]
+pub fn payload_addr() -> usize {
+    PAYLOAD.as_ptr() as usize
+}
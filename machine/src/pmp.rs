@@ -0,0 +1,132 @@
+//! Physical memory protection keeping supervisor software away from firmware memory.
+
+use core::{arch::asm, ops::Range};
+use riscv::register::{
+    pmpaddr0, pmpaddr1, pmpaddr10, pmpaddr11, pmpaddr12, pmpaddr13, pmpaddr14, pmpaddr15, pmpaddr2,
+    pmpaddr3, pmpaddr4, pmpaddr5, pmpaddr6, pmpaddr7, pmpaddr8, pmpaddr9, pmpcfg0, pmpcfg2,
+    Permission, Range as PmpRange,
+};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Memory occupied by firmware text, data and stacks, which must be reserved from supervisor.
+#[inline]
+pub fn firmware_region() -> Range<usize> {
+    let (start, end): (usize, usize);
+    // symbols defined in linker script
+    unsafe { asm!("la {}, sfirmware", "la {}, efirmware", out(reg) start, out(reg) end) };
+    start & !(PAGE_SIZE - 1)..(end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Number of PMP entries and their granularity in bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct PmpInfo {
+    pub count: usize,
+    pub granularity: usize,
+}
+
+/// Detects PMP entries of current hart; `None` if PMP is not implemented.
+///
+/// Unimplemented `pmpaddr` registers are hardwired to zero, and implemented ones
+/// are always the lowest-numbered. Entries are switched off before probing so that
+/// probe values never take effect.
+pub fn detect() -> Option<PmpInfo> {
+    pmpcfg0::write(0);
+    pmpcfg2::write(0);
+    let addr = write_read_pmpaddr(0, usize::MAX);
+    if addr == 0 {
+        return None;
+    }
+    // G is the index of the lowest writable bit
+    let granularity = 1 << (addr.trailing_zeros() + 2);
+    let mut count = (0..16)
+        .find(|&index| write_read_pmpaddr(index, usize::MAX) == 0)
+        .unwrap_or(16);
+    // cores with 16 entries may not implement `pmpaddr63` at all
+    if count == 16 && unsafe { write_read_pmpaddr63_or_fault(usize::MAX) } != 0 {
+        count = 64;
+        unsafe { write_read_pmpaddr63_or_fault(0) };
+    }
+    for index in 0..16 {
+        write_read_pmpaddr(index, 0);
+    }
+    Some(PmpInfo { count, granularity })
+}
+
+/// Denies supervisor and user access to firmware memory, allowing all other memory.
+pub fn init(hart_id: usize) {
+    let Some(info) = detect() else {
+        trace!("hart {} has no PMP", hart_id);
+        return;
+    };
+    if info.count < 3 {
+        warn!(
+            "hart {} has only {} PMP entries, firmware memory is not protected",
+            hart_id, info.count
+        );
+        return;
+    }
+    let region = firmware_region();
+    let granularity = info.granularity.max(4);
+    let start = region.start & !(granularity - 1);
+    let end = (region.end + granularity - 1) & !(granularity - 1);
+    trace!(
+        "hart {} PMP: {} entries, granularity {} bytes, firmware {:x?}",
+        hart_id,
+        info.count,
+        info.granularity,
+        start..end
+    );
+    pmpaddr0::write(start >> 2);
+    pmpaddr1::write(end >> 2);
+    // NAPOT with all ones covers the whole physical address space
+    pmpaddr2::write(usize::MAX);
+    unsafe {
+        pmpcfg0::set_pmp(0, PmpRange::OFF, Permission::NONE, false);
+        pmpcfg0::set_pmp(1, PmpRange::TOR, Permission::NONE, false);
+        pmpcfg0::set_pmp(2, PmpRange::NAPOT, Permission::RWX, false);
+        asm!("sfence.vma");
+    }
+}
+
+/// Writes `value` into `pmpaddr` register of `index` and reads it back.
+fn write_read_pmpaddr(index: usize, value: usize) -> usize {
+    macro_rules! write_read {
+        ($($index:literal => $reg:ident),*) => {
+            match index {
+                $($index => {
+                    $reg::write(value);
+                    $reg::read()
+                })*
+                _ => unreachable!(),
+            }
+        };
+    }
+    write_read!(
+        0 => pmpaddr0, 1 => pmpaddr1, 2 => pmpaddr2, 3 => pmpaddr3,
+        4 => pmpaddr4, 5 => pmpaddr5, 6 => pmpaddr6, 7 => pmpaddr7,
+        8 => pmpaddr8, 9 => pmpaddr9, 10 => pmpaddr10, 11 => pmpaddr11,
+        12 => pmpaddr12, 13 => pmpaddr13, 14 => pmpaddr14, 15 => pmpaddr15
+    )
+}
+
+/// Switches off entries 56 to 63, then writes `pmpaddr63` and reads it back, or returns 0
+/// if `pmpcfg14` or `pmpaddr63` is not implemented.
+#[naked]
+unsafe extern "C" fn write_read_pmpaddr63_or_fault(value: usize) -> usize {
+    asm!(
+        // temporarily redirect machine traps to label 2
+        "   csrr    t1, mtvec
+            la      t0, 2f
+            csrw    mtvec, t0
+            csrw    0x3ae, zero
+            csrw    0x3ef, a0
+            csrr    a0, 0x3ef
+            j       3f",
+        ".align 2",
+        "2: li      a0, 0",
+        "3: csrw    mtvec, t1
+            ret",
+        options(noreturn)
+    )
+}
@@ -0,0 +1,525 @@
+//! Performance monitoring through hardware performance counters and firmware event counters.
+//!
+//! Logical counter indices 0 to 31 are `mcycle`, `minstret` and `mhpmcounter3` to
+//! `mhpmcounter31` by their CSR offset, followed by firmware counters kept in memory.
+//! Hardware events other than cycles and instructions are mapped to `mhpmevent` values
+//! by the `riscv,pmu` device tree node.
+
+use crate::NUM_HART_MAX;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+use riscv::register::{mcounteren, mhartid};
+use rustsbi::spec::pmu::firmware_event;
+#[cfg(feature = "pmu")]
+use rustsbi::{
+    spec::pmu::{
+        event_type,
+        flags::{CounterCfgFlags, CounterStartFlags, CounterStopFlags},
+        hardware_event,
+    },
+    SbiRet,
+};
+#[cfg(feature = "pmu")]
+use spin::Mutex;
+
+/// Number of hardware counter indices, `mcycle` to `mhpmcounter31`.
+const NUM_HW_COUNTERS: usize = 32;
+/// Number of firmware counters of each hart.
+#[cfg(feature = "pmu")]
+const NUM_FW_COUNTERS: usize = 16;
+#[cfg(feature = "pmu")]
+const NUM_COUNTERS: usize = NUM_HW_COUNTERS + NUM_FW_COUNTERS;
+/// Number of firmware events defined by SBI, excluding platform specific events.
+const NUM_FW_EVENTS: usize = firmware_event::HFENCE_VVMA_ASID_RECEIVED + 1;
+/// Firmware events recorded by this firmware; misaligned accesses, access faults and illegal
+/// instructions are delegated to supervisor software, so those events are not offered.
+#[cfg(feature = "pmu")]
+const COUNTED_FW_EVENTS: core::ops::Range<usize> = firmware_event::SET_TIMER..NUM_FW_EVENTS;
+/// Maximum number of entries of each mapping kept from the device tree.
+const NUM_EVENT_MAP_MAX: usize = 16;
+
+#[cfg(feature = "pmu")]
+const CYCLE: usize = 0;
+#[cfg(feature = "pmu")]
+const INSTRET: usize = 2;
+/// `mcountinhibit` bits of `mhpmcounter3` to `mhpmcounter31`.
+const INHIBIT_HPM: usize = 0xffff_fff8;
+
+/// Hardware event mapping of the `riscv,pmu` device tree node.
+#[derive(Default)]
+pub struct EventMap {
+    /// First and last event index, and the bitmap of counters able to count them.
+    counters: [(u32, u32, u32); NUM_EVENT_MAP_MAX],
+    num_counters: usize,
+    /// Event index and the `mhpmevent` value selecting it.
+    selectors: [(u32, u64); NUM_EVENT_MAP_MAX],
+    num_selectors: usize,
+    /// Raw event value and mask, and the bitmap of counters able to count matching events.
+    raw_counters: [(u64, u64, u32); NUM_EVENT_MAP_MAX],
+    num_raw_counters: usize,
+}
+
+impl EventMap {
+    /// Adds an entry of `riscv,event-to-mhpmcounters`, returning `false` if the map is full.
+    #[inline]
+    pub fn add_counters(&mut self, first: u32, last: u32, counters: u32) -> bool {
+        push(
+            &mut self.counters,
+            &mut self.num_counters,
+            (first, last, counters),
+        )
+    }
+
+    /// Adds an entry of `riscv,event-to-mhpmevent`, returning `false` if the map is full.
+    #[inline]
+    pub fn add_selector(&mut self, event_idx: u32, selector: u64) -> bool {
+        push(
+            &mut self.selectors,
+            &mut self.num_selectors,
+            (event_idx, selector),
+        )
+    }
+
+    /// Adds an entry of `riscv,raw-event-to-mhpmcounters`, returning `false` if the map is full.
+    #[inline]
+    pub fn add_raw_counters(&mut self, value: u64, mask: u64, counters: u32) -> bool {
+        push(
+            &mut self.raw_counters,
+            &mut self.num_raw_counters,
+            (value, mask, counters),
+        )
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.num_counters == 0 && self.num_raw_counters == 0
+    }
+
+    /// Bitmap of counters able to count the event, and the `mhpmevent` value selecting it.
+    ///
+    /// Events without a selector in the map are selected by their event index, as QEMU does.
+    #[cfg(feature = "pmu")]
+    fn lookup(&self, event_idx: usize, event_data: u64) -> Option<(u32, usize)> {
+        let (counters, selector) = if event_idx >> 16 == event_type::HARDWARE_RAW {
+            let counters = self.raw_counters[..self.num_raw_counters]
+                .iter()
+                .filter(|&&(value, mask, _)| event_data & mask == value)
+                .fold(0, |bitmap, &(_, _, counters)| bitmap | counters);
+            (counters, event_data as usize)
+        } else {
+            let counters = self.counters[..self.num_counters]
+                .iter()
+                .filter(|&&(first, last, _)| (first as usize..=last as usize).contains(&event_idx))
+                .fold(0, |bitmap, &(_, _, counters)| bitmap | counters);
+            let selector = self.selectors[..self.num_selectors]
+                .iter()
+                .find(|&&(idx, _)| idx as usize == event_idx)
+                .map_or(event_idx, |&(_, selector)| selector as usize);
+            (counters, selector)
+        };
+        (counters != 0).then_some((counters, selector))
+    }
+}
+
+impl core::fmt::Debug for EventMap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EventMap")
+            .field("counters", &&self.counters[..self.num_counters])
+            .field("selectors", &&self.selectors[..self.num_selectors])
+            .field("raw_counters", &&self.raw_counters[..self.num_raw_counters])
+            .finish()
+    }
+}
+
+static EVENT_MAP: spin::Once<EventMap> = spin::Once::new();
+
+#[cfg(feature = "fdt")]
+pub fn load_event_map(map: EventMap) {
+    EVENT_MAP.call_once(|| map);
+}
+
+/// Implemented `mhpmcounter`s as a bitmap of counter indices.
+///
+/// Harts are assumed to implement the same counters.
+static HPM_COUNTERS: AtomicUsize = AtomicUsize::new(0);
+/// Whether `mcountinhibit` is implemented; counters cannot be stopped without it.
+static HAS_INHIBIT: AtomicBool = AtomicBool::new(false);
+
+/// Number of firmware events occurred on each hart.
+#[allow(clippy::declare_interior_mutable_const)]
+const FW_EVENT_INIT: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const FW_EVENTS_INIT: [AtomicU64; NUM_FW_EVENTS] = [FW_EVENT_INIT; NUM_FW_EVENTS];
+static FW_EVENTS: [[AtomicU64; NUM_FW_EVENTS]; NUM_HART_MAX] = [FW_EVENTS_INIT; NUM_HART_MAX];
+
+/// Counts a firmware event, one of `firmware_event` constants, on current hart.
+#[inline]
+pub fn record(event: usize) {
+    if let Some(count) =
+        crate::hart::entry_of(&FW_EVENTS, mhartid::read()).and_then(|events| events.get(event))
+    {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counter configuration of one hart.
+#[cfg(feature = "pmu")]
+struct HartPmu {
+    /// Event index configured on each counter, or `None` if the counter is free.
+    events: [Option<usize>; NUM_COUNTERS],
+    /// Bitmap of started counters.
+    started: u64,
+    /// Firmware counter values when last written or stopped.
+    fw_values: [u64; NUM_FW_COUNTERS],
+    /// Firmware event counts when each firmware counter was last written or started.
+    fw_bases: [u64; NUM_FW_COUNTERS],
+}
+
+#[cfg(feature = "pmu")]
+impl HartPmu {
+    const fn new() -> Self {
+        Self {
+            events: [None; NUM_COUNTERS],
+            started: 0,
+            fw_values: [0; NUM_FW_COUNTERS],
+            fw_bases: [0; NUM_FW_COUNTERS],
+        }
+    }
+
+    /// Finds a free counter among `candidates` able to count the event, with its `mhpmevent` value.
+    fn find_counter(
+        &self,
+        candidates: u64,
+        event_idx: usize,
+        event_data: u64,
+    ) -> Option<(usize, usize)> {
+        let is_free = |index: usize| candidates & (1 << index) != 0 && self.events[index].is_none();
+        match (event_idx >> 16, event_idx & 0xffff) {
+            (event_type::FIRMWARE, code) => {
+                if !COUNTED_FW_EVENTS.contains(&code) {
+                    return None;
+                }
+                (NUM_HW_COUNTERS..NUM_COUNTERS)
+                    .find(|&index| is_free(index))
+                    .map(|index| (index, 0))
+            }
+            (event_type::HARDWARE_GENERAL, hardware_event::CPU_CYCLES) if is_free(CYCLE) => {
+                Some((CYCLE, 0))
+            }
+            (event_type::HARDWARE_GENERAL, hardware_event::INSTRUCTIONS) if is_free(INSTRET) => {
+                Some((INSTRET, 0))
+            }
+            _ => {
+                let (counters, selector) = EVENT_MAP.get()?.lookup(event_idx, event_data)?;
+                (3..NUM_HW_COUNTERS)
+                    .find(|&index| counters & (1 << index) != 0 && is_free(index))
+                    .map(|index| (index, selector))
+            }
+        }
+    }
+
+    #[inline]
+    fn is_started(&self, index: usize) -> bool {
+        self.started & (1 << index) != 0
+    }
+
+    fn start(&mut self, hart_id: usize, index: usize) {
+        if let Some(fw) = index.checked_sub(NUM_HW_COUNTERS) {
+            self.fw_bases[fw] = self.fw_event_count(hart_id, index);
+        } else if HAS_INHIBIT.load(Ordering::Relaxed) {
+            unsafe { asm!("csrc 0x320, {}", in(reg) 1usize << index) };
+        }
+        self.started |= 1 << index;
+    }
+
+    fn stop(&mut self, hart_id: usize, index: usize) {
+        if let Some(fw) = index.checked_sub(NUM_HW_COUNTERS) {
+            self.fw_values[fw] = self.read_fw(hart_id, index);
+        } else if HAS_INHIBIT.load(Ordering::Relaxed) {
+            unsafe { asm!("csrs 0x320, {}", in(reg) 1usize << index) };
+        }
+        self.started &= !(1 << index);
+    }
+
+    fn write(&mut self, hart_id: usize, index: usize, value: u64) {
+        if let Some(fw) = index.checked_sub(NUM_HW_COUNTERS) {
+            self.fw_values[fw] = value;
+            self.fw_bases[fw] = self.fw_event_count(hart_id, index);
+        } else {
+            write_counter(index, value as usize);
+        }
+    }
+
+    /// Value of a firmware counter, counting events since it was started if running.
+    fn read_fw(&self, hart_id: usize, index: usize) -> u64 {
+        let fw = index - NUM_HW_COUNTERS;
+        if self.is_started(index) {
+            let delta = self.fw_event_count(hart_id, index) - self.fw_bases[fw];
+            self.fw_values[fw].wrapping_add(delta)
+        } else {
+            self.fw_values[fw]
+        }
+    }
+
+    /// Number of events occurred on current hart of the event configured on firmware counter.
+    #[inline]
+    fn fw_event_count(&self, hart_id: usize, index: usize) -> u64 {
+        let event = self.events[index].unwrap_or(0) & 0xffff;
+        crate::hart::entry_of(&FW_EVENTS, hart_id)
+            .map_or(0, |events| events[event].load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(feature = "pmu")]
+#[allow(clippy::declare_interior_mutable_const)]
+const HART_PMU_INIT: Mutex<HartPmu> = Mutex::new(HartPmu::new());
+#[cfg(feature = "pmu")]
+static HARTS: [Mutex<HartPmu>; NUM_HART_MAX] = [HART_PMU_INIT; NUM_HART_MAX];
+
+/// Counter state of a usable hart; only usable harts run supervisor software.
+#[cfg(feature = "pmu")]
+#[inline]
+fn hart_pmu(hart_id: usize) -> &'static Mutex<HartPmu> {
+    crate::hart::entry_of(&HARTS, hart_id).expect("hart is usable")
+}
+
+/// Detects hardware counters of current hart, stops all `mhpmcounter`s
+/// and allows supervisor software to read implemented counters.
+pub fn init(hart_id: usize) {
+    let has_inhibit = unsafe { write_mcountinhibit_or_fault(INHIBIT_HPM) } != 0;
+    let mut hpm_counters = 0;
+    for index in 3..NUM_HW_COUNTERS {
+        // unimplemented counters are read-only zero
+        write_event(index, 0);
+        write_counter(index, 1);
+        if read_counter(index) != 0 {
+            hpm_counters |= 1 << index;
+            unsafe { mcounteren::set_hpm(index) };
+        }
+        write_counter(index, 0);
+    }
+    trace!(
+        "hart {} has mhpmcounter bitmap 0x{:x}, mcountinhibit {}",
+        hart_id,
+        hpm_counters,
+        if has_inhibit { "present" } else { "absent" }
+    );
+    HPM_COUNTERS.store(hpm_counters, Ordering::Relaxed);
+    HAS_INHIBIT.store(has_inhibit, Ordering::Relaxed);
+    // counters of a restarted hart begin unconfigured
+    #[cfg(feature = "pmu")]
+    {
+        *hart_pmu(hart_id).lock() = HartPmu::new();
+    }
+}
+
+#[cfg(feature = "pmu")]
+#[inline]
+fn is_valid(index: usize) -> bool {
+    match index {
+        CYCLE | INSTRET => true,
+        3..=31 => HPM_COUNTERS.load(Ordering::Relaxed) & (1 << index) != 0,
+        _ => (NUM_HW_COUNTERS..NUM_COUNTERS).contains(&index),
+    }
+}
+
+/// Bitmap of counters selected by `counter_idx_base` and `counter_idx_mask`,
+/// or `None` if any of them is not valid.
+#[cfg(feature = "pmu")]
+fn counter_bitmap(counter_idx_base: usize, counter_idx_mask: usize) -> Option<u64> {
+    let mut bitmap = 0;
+    for bit in 0..usize::BITS as usize {
+        if counter_idx_mask & (1 << bit) == 0 {
+            continue;
+        }
+        let index = counter_idx_base.checked_add(bit)?;
+        if !is_valid(index) {
+            return None;
+        }
+        bitmap |= 1 << index;
+    }
+    Some(bitmap)
+}
+
+#[cfg(feature = "pmu")]
+#[inline]
+fn counter_indices(bitmap: u64) -> impl Iterator<Item = usize> {
+    (0..NUM_COUNTERS).filter(move |index| bitmap & (1 << index) != 0)
+}
+
+/// SBI PMU extension using hardware performance counters and firmware event counters.
+#[cfg(feature = "pmu")]
+pub struct MachinePmu;
+
+#[cfg(feature = "pmu")]
+impl rustsbi::Pmu for MachinePmu {
+    #[inline]
+    fn num_counters(&self) -> usize {
+        NUM_COUNTERS
+    }
+
+    fn counter_get_info(&self, counter_idx: usize) -> SbiRet {
+        if !is_valid(counter_idx) {
+            SbiRet::invalid_param()
+        } else if counter_idx < NUM_HW_COUNTERS {
+            // 64-bit counter read through `cycle`, `instret` or `hpmcounter` CSR
+            SbiRet::success((0xc00 + counter_idx) | (63 << 12))
+        } else {
+            SbiRet::success(1 << (usize::BITS - 1))
+        }
+    }
+
+    fn counter_config_matching(
+        &self,
+        counter_idx_base: usize,
+        counter_idx_mask: usize,
+        config_flags: usize,
+        event_idx: usize,
+        event_data: u64,
+    ) -> SbiRet {
+        let Some(candidates) = counter_bitmap(counter_idx_base, counter_idx_mask) else {
+            return SbiRet::invalid_param();
+        };
+        let flags = CounterCfgFlags::from_bits_truncate(config_flags);
+        let hart_id = mhartid::read();
+        let mut pmu = hart_pmu(hart_id).lock();
+        let index = if flags.contains(CounterCfgFlags::SKIP_MATCH) {
+            match counter_indices(candidates).find(|&index| pmu.events[index].is_some()) {
+                Some(index) => index,
+                None => return SbiRet::invalid_param(),
+            }
+        } else {
+            let Some((index, selector)) = pmu.find_counter(candidates, event_idx, event_data)
+            else {
+                return SbiRet::not_supported();
+            };
+            pmu.events[index] = Some(event_idx);
+            write_event(index, selector);
+            index
+        };
+        if flags.contains(CounterCfgFlags::CLEAR_VALUE) {
+            pmu.write(hart_id, index, 0);
+        }
+        if flags.contains(CounterCfgFlags::AUTO_START) && !pmu.is_started(index) {
+            pmu.start(hart_id, index);
+        }
+        SbiRet::success(index)
+    }
+
+    fn counter_start(
+        &self,
+        counter_idx_base: usize,
+        counter_idx_mask: usize,
+        start_flags: usize,
+        initial_value: u64,
+    ) -> SbiRet {
+        let Some(bitmap) = counter_bitmap(counter_idx_base, counter_idx_mask) else {
+            return SbiRet::invalid_param();
+        };
+        let flags = CounterStartFlags::from_bits_truncate(start_flags);
+        if flags.contains(CounterStartFlags::INIT_SNAPSHOT) {
+            return SbiRet::no_shmem();
+        }
+        let hart_id = mhartid::read();
+        let mut pmu = hart_pmu(hart_id).lock();
+        if counter_indices(bitmap).any(|index| pmu.events[index].is_none()) {
+            return SbiRet::invalid_param();
+        }
+        let mut ans = SbiRet::success(0);
+        for index in counter_indices(bitmap) {
+            if pmu.is_started(index) {
+                ans = SbiRet::already_started();
+                continue;
+            }
+            if flags.contains(CounterStartFlags::INIT_VALUE) {
+                pmu.write(hart_id, index, initial_value);
+            }
+            pmu.start(hart_id, index);
+        }
+        ans
+    }
+
+    fn counter_stop(
+        &self,
+        counter_idx_base: usize,
+        counter_idx_mask: usize,
+        stop_flags: usize,
+    ) -> SbiRet {
+        let Some(bitmap) = counter_bitmap(counter_idx_base, counter_idx_mask) else {
+            return SbiRet::invalid_param();
+        };
+        let flags = CounterStopFlags::from_bits_truncate(stop_flags);
+        if flags.contains(CounterStopFlags::TAKE_SNAPSHOT) {
+            return SbiRet::no_shmem();
+        }
+        let hart_id = mhartid::read();
+        let mut pmu = hart_pmu(hart_id).lock();
+        if counter_indices(bitmap).any(|index| pmu.events[index].is_none()) {
+            return SbiRet::invalid_param();
+        }
+        let mut ans = SbiRet::success(0);
+        for index in counter_indices(bitmap) {
+            if pmu.is_started(index) {
+                pmu.stop(hart_id, index);
+            } else {
+                ans = SbiRet::already_stopped();
+            }
+            // stopped counters may still be released from their events
+            if flags.contains(CounterStopFlags::RESET) {
+                pmu.events[index] = None;
+                write_event(index, 0);
+            }
+        }
+        ans
+    }
+
+    fn counter_fw_read(&self, counter_idx: usize) -> SbiRet {
+        let hart_id = mhartid::read();
+        let pmu = hart_pmu(hart_id).lock();
+        match pmu.events.get(counter_idx) {
+            Some(Some(_)) if counter_idx >= NUM_HW_COUNTERS => {
+                SbiRet::success(pmu.read_fw(hart_id, counter_idx) as usize)
+            }
+            _ => SbiRet::invalid_param(),
+        }
+    }
+}
+
+// `mcycle`, `minstret` and `mhpmcounter3` to `mhpmcounter31`
+csr_by_index!(read read_counter, 0xb00, 0 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31);
+csr_by_index!(write write_counter, 0xb00, 0 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31);
+// `mhpmevent3` to `mhpmevent31`
+csr_by_index!(write write_event, 0x320, 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31);
+
+/// Writes `mcountinhibit` and returns non-zero, or returns 0 if `mcountinhibit` is not implemented.
+#[naked]
+unsafe extern "C" fn write_mcountinhibit_or_fault(value: usize) -> usize {
+    asm!(
+        // temporarily redirect machine traps to label 2
+        "   csrr    t1, mtvec
+            la      t0, 2f
+            csrw    mtvec, t0
+            csrw    0x320, a0
+            li      a0, 1
+            j       3f",
+        ".align 2",
+        "2: li      a0, 0",
+        "3: csrw    mtvec, t1
+            ret",
+        options(noreturn)
+    )
+}
+
+#[inline]
+fn push<T>(entries: &mut [T], len: &mut usize, entry: T) -> bool {
+    match entries.get_mut(*len) {
+        Some(slot) => {
+            *slot = entry;
+            *len += 1;
+            true
+        }
+        None => false,
+    }
+}
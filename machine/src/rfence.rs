@@ -0,0 +1,351 @@
+//! Remote fences through per-hart mailboxes.
+//!
+//! The calling hart posts a fence request into the mailbox of every target hart, raises
+//! its machine software interrupt and waits until all of them have executed the fence.
+
+use crate::NUM_HART_MAX;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use riscv::register::mhartid;
+use rustsbi::{spec::pmu::firmware_event, HartMask, SbiRet};
+use spin::Mutex;
+
+/// Flush the whole address space instead of page by page above this size.
+const FULL_FLUSH_THRESHOLD: usize = 64 * PAGE_SIZE;
+const PAGE_SIZE: usize = 4096;
+const HGATP_VMID_SHIFT: u32 = 44;
+const HGATP_VMID: usize = 0x3fff << HGATP_VMID_SHIFT;
+
+#[derive(Clone, Copy, Debug)]
+enum FenceOp {
+    FenceI,
+    SfenceVma {
+        start: usize,
+        size: usize,
+    },
+    SfenceVmaAsid {
+        start: usize,
+        size: usize,
+        asid: usize,
+    },
+    HfenceGvmaVmid {
+        start: usize,
+        size: usize,
+        vmid: usize,
+    },
+    HfenceGvma {
+        start: usize,
+        size: usize,
+    },
+    /// `vmid` is the VMID of the sending hart, which `hfence.vvma` applies to.
+    HfenceVvmaAsid {
+        start: usize,
+        size: usize,
+        asid: usize,
+        vmid: usize,
+    },
+    HfenceVvma {
+        start: usize,
+        size: usize,
+        vmid: usize,
+    },
+}
+
+impl FenceOp {
+    /// Firmware events of sending this fence to a hart and of executing it.
+    fn fw_events(self) -> (usize, usize) {
+        use firmware_event::*;
+        match self {
+            FenceOp::FenceI => (FENCE_I_SENT, FENCE_I_RECEIVED),
+            FenceOp::SfenceVma { .. } => (SFENCE_VMA_SENT, SFENCE_VMA_RECEIVED),
+            FenceOp::SfenceVmaAsid { .. } => (SFENCE_VMA_ASID_SENT, SFENCE_VMA_ASID_RECEIVED),
+            FenceOp::HfenceGvmaVmid { .. } => (HFENCE_GVMA_VMID_SENT, HFENCE_GVMA_VMID_RECEIVED),
+            FenceOp::HfenceGvma { .. } => (HFENCE_GVMA_SENT, HFENCE_GVMA_RECEIVED),
+            FenceOp::HfenceVvmaAsid { .. } => (HFENCE_VVMA_ASID_SENT, HFENCE_VVMA_ASID_RECEIVED),
+            FenceOp::HfenceVvma { .. } => (HFENCE_VVMA_SENT, HFENCE_VVMA_RECEIVED),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FenceRequest {
+    op: FenceOp,
+    from: usize,
+}
+
+struct Mailbox {
+    request: Mutex<Option<FenceRequest>>,
+    /// Number of requests sent by this hart not yet acknowledged by target harts.
+    pending: AtomicUsize,
+}
+
+impl Mailbox {
+    const fn new() -> Self {
+        Self {
+            request: Mutex::new(None),
+            pending: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const MAILBOX_INIT: Mailbox = Mailbox::new();
+static MAILBOXES: [Mailbox; NUM_HART_MAX] = [MAILBOX_INIT; NUM_HART_MAX];
+
+/// Mailbox of a usable hart; only usable harts send and receive remote fences.
+#[inline]
+fn mailbox_of(hart_id: usize) -> &'static Mailbox {
+    crate::hart::entry_of(&MAILBOXES, hart_id).expect("hart is usable")
+}
+
+/// Executes the fence request posted to current hart, if any, and acknowledges its sender.
+///
+/// Called on machine software interrupts, and by harts waiting in machine mode.
+pub fn handle_pending(hart_id: usize) {
+    let Some(mailbox) = crate::hart::entry_of(&MAILBOXES, hart_id) else {
+        return;
+    };
+    let request = mailbox.request.lock().take();
+    if let Some(FenceRequest { op, from }) = request {
+        execute(op);
+        mailbox_of(from).pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Runs `op` on every hart in `hart_mask` and waits until all of them are done.
+fn remote_fence(hart_mask: HartMask, op: FenceOp) -> SbiRet {
+    if !crate::hart::is_valid_mask(hart_mask) {
+        return SbiRet::invalid_param();
+    }
+    let current = mhartid::read();
+    let mut execute_local = false;
+    for (hart_id, mailbox) in crate::hart::hart_ids().zip(&MAILBOXES) {
+        if !hart_mask.has_bit(hart_id) {
+            continue;
+        }
+        if hart_id == current {
+            crate::pmu::record(op.fw_events().0);
+            execute_local = true;
+            continue;
+        }
+        // stopped harts have no supervisor translations or instructions to fence
+        if !crate::hsm::is_available(hart_id) {
+            continue;
+        }
+        if !crate::clint::is_present() {
+            error!("remote fence to hart {} without CLINT", hart_id);
+            return SbiRet::failed();
+        }
+        mailbox_of(current).pending.fetch_add(1, Ordering::AcqRel);
+        loop {
+            let mut slot = mailbox.request.lock();
+            if slot.is_none() {
+                *slot = Some(FenceRequest { op, from: current });
+                break;
+            }
+            drop(slot);
+            // other harts may be waiting for us while we wait for the mailbox
+            handle_pending(current);
+        }
+        crate::clint::send_ipi(hart_id, crate::clint::IPI_FENCE);
+        crate::pmu::record(op.fw_events().0);
+    }
+    if execute_local {
+        execute(op);
+    }
+    while mailbox_of(current).pending.load(Ordering::Acquire) != 0 {
+        handle_pending(current);
+        core::hint::spin_loop()
+    }
+    SbiRet::success(0)
+}
+
+fn execute(op: FenceOp) {
+    trace!("hart {} executes {:x?}", mhartid::read(), op);
+    crate::pmu::record(op.fw_events().1);
+    unsafe {
+        match op {
+            FenceOp::FenceI => asm!("fence.i"),
+            FenceOp::SfenceVma { start, size } => for_each_page(start, size, |addr| match addr {
+                Some(addr) => asm!("sfence.vma {}, zero", in(reg) addr),
+                None => asm!("sfence.vma"),
+            }),
+            FenceOp::SfenceVmaAsid { start, size, asid } => {
+                for_each_page(start, size, |addr| match addr {
+                    Some(addr) => asm!("sfence.vma {}, {}", in(reg) addr, in(reg) asid),
+                    None => asm!("sfence.vma zero, {}", in(reg) asid),
+                })
+            }
+            // hfence.gvma rs1, rs2
+            FenceOp::HfenceGvmaVmid { start, size, vmid } => {
+                for_each_page(start, size, |addr| match addr {
+                    Some(addr) => {
+                        asm!(".insn r 0x73, 0, 0x31, x0, {}, {}", in(reg) addr >> 2, in(reg) vmid)
+                    }
+                    None => asm!(".insn r 0x73, 0, 0x31, x0, x0, {}", in(reg) vmid),
+                })
+            }
+            FenceOp::HfenceGvma { start, size } => for_each_page(start, size, |addr| match addr {
+                Some(addr) => asm!(".insn r 0x73, 0, 0x31, x0, {}, x0", in(reg) addr >> 2),
+                None => asm!(".insn r 0x73, 0, 0x31, x0, x0, x0"),
+            }),
+            // hfence.vvma rs1, rs2
+            FenceOp::HfenceVvmaAsid {
+                start,
+                size,
+                asid,
+                vmid,
+            } => with_vmid(vmid, || {
+                for_each_page(start, size, |addr| match addr {
+                    Some(addr) => {
+                        asm!(".insn r 0x73, 0, 0x11, x0, {}, {}", in(reg) addr, in(reg) asid)
+                    }
+                    None => asm!(".insn r 0x73, 0, 0x11, x0, x0, {}", in(reg) asid),
+                })
+            }),
+            FenceOp::HfenceVvma { start, size, vmid } => with_vmid(vmid, || {
+                for_each_page(start, size, |addr| match addr {
+                    Some(addr) => asm!(".insn r 0x73, 0, 0x11, x0, {}, x0", in(reg) addr),
+                    None => asm!(".insn r 0x73, 0, 0x11, x0, x0, x0"),
+                })
+            }),
+        }
+    }
+}
+
+/// VMID in `hgatp` of current hart.
+#[inline]
+fn current_vmid() -> usize {
+    let hgatp: usize;
+    unsafe { asm!("csrr {}, 0x680", out(reg) hgatp) };
+    (hgatp & HGATP_VMID) >> HGATP_VMID_SHIFT
+}
+
+/// Runs `f` with VMID in `hgatp` of current hart temporarily set to `vmid`,
+/// so that `hfence.vvma` applies to that guest.
+pub fn with_vmid(vmid: usize, f: impl FnOnce()) {
+    let hgatp: usize;
+    unsafe {
+        asm!("csrr {}, 0x680", out(reg) hgatp);
+        let swapped = hgatp & !HGATP_VMID | (vmid << HGATP_VMID_SHIFT) & HGATP_VMID;
+        asm!("csrw 0x680, {}", in(reg) swapped);
+    }
+    f();
+    unsafe { asm!("csrw 0x680, {}", in(reg) hgatp) };
+}
+
+/// Calls `f` on each page of the range, or once with `None` to flush the whole address space.
+#[inline]
+fn for_each_page(start: usize, size: usize, mut f: impl FnMut(Option<usize>)) {
+    if (start == 0 && size == 0) || size == usize::MAX || size > FULL_FLUSH_THRESHOLD {
+        f(None);
+        return;
+    }
+    let mut addr = start & !(PAGE_SIZE - 1);
+    let end = start.saturating_add(size);
+    while addr < end {
+        f(Some(addr));
+        addr += PAGE_SIZE;
+    }
+}
+
+/// SBI RFENCE extension using CLINT software interrupts.
+pub struct MachineRfence;
+
+impl rustsbi::Fence for MachineRfence {
+    #[inline]
+    fn remote_fence_i(&self, hart_mask: HartMask) -> SbiRet {
+        remote_fence(hart_mask, FenceOp::FenceI)
+    }
+
+    #[inline]
+    fn remote_sfence_vma(&self, hart_mask: HartMask, start_addr: usize, size: usize) -> SbiRet {
+        let op = FenceOp::SfenceVma {
+            start: start_addr,
+            size,
+        };
+        remote_fence(hart_mask, op)
+    }
+
+    #[inline]
+    fn remote_sfence_vma_asid(
+        &self,
+        hart_mask: HartMask,
+        start_addr: usize,
+        size: usize,
+        asid: usize,
+    ) -> SbiRet {
+        let op = FenceOp::SfenceVmaAsid {
+            start: start_addr,
+            size,
+            asid,
+        };
+        remote_fence(hart_mask, op)
+    }
+
+    #[inline]
+    fn remote_hfence_gvma_vmid(
+        &self,
+        hart_mask: HartMask,
+        start_addr: usize,
+        size: usize,
+        vmid: usize,
+    ) -> SbiRet {
+        if !crate::hart::has_hypervisor() {
+            return SbiRet::not_supported();
+        }
+        let op = FenceOp::HfenceGvmaVmid {
+            start: start_addr,
+            size,
+            vmid,
+        };
+        remote_fence(hart_mask, op)
+    }
+
+    #[inline]
+    fn remote_hfence_gvma(&self, hart_mask: HartMask, start_addr: usize, size: usize) -> SbiRet {
+        if !crate::hart::has_hypervisor() {
+            return SbiRet::not_supported();
+        }
+        let op = FenceOp::HfenceGvma {
+            start: start_addr,
+            size,
+        };
+        remote_fence(hart_mask, op)
+    }
+
+    #[inline]
+    fn remote_hfence_vvma_asid(
+        &self,
+        hart_mask: HartMask,
+        start_addr: usize,
+        size: usize,
+        asid: usize,
+    ) -> SbiRet {
+        if !crate::hart::has_hypervisor() {
+            return SbiRet::not_supported();
+        }
+        let op = FenceOp::HfenceVvmaAsid {
+            start: start_addr,
+            size,
+            asid,
+            vmid: current_vmid(),
+        };
+        remote_fence(hart_mask, op)
+    }
+
+    #[inline]
+    fn remote_hfence_vvma(&self, hart_mask: HartMask, start_addr: usize, size: usize) -> SbiRet {
+        if !crate::hart::has_hypervisor() {
+            return SbiRet::not_supported();
+        }
+        let op = FenceOp::HfenceVvma {
+            start: start_addr,
+            size,
+            vmid: current_vmid(),
+        };
+        remote_fence(hart_mask, op)
+    }
+}
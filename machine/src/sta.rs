@@ -0,0 +1,112 @@
+//! Steal-time accounting of machine-mode interrupt handling.
+//!
+//! Interrupts taken from supervisor software, e.g. remote fences and forwarded timer
+//! interrupts, keep its hart from running; the hart is reported as preempted while they
+//! are handled, and their duration is added to stolen time in nanoseconds of `mtime`.
+
+use crate::NUM_HART_MAX;
+use core::{
+    ptr::{self, addr_of_mut},
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+use riscv::register::mhartid;
+#[cfg(feature = "sta")]
+use rustsbi::{spec::binary::SharedPtr, SbiRet};
+
+/// Size and alignment of the steal-time record.
+#[cfg(feature = "sta")]
+const RECORD_SIZE: usize = 64;
+/// Value of per-hart record address when reporting is disabled, same as in `set_shmem`.
+const NO_SHMEM: usize = usize::MAX;
+
+/// Steal-time record shared with supervisor software.
+///
+/// `sequence` is odd while the record is being updated; readers retry until it is even
+/// and unchanged across their reads.
+#[repr(C)]
+struct StealTime {
+    sequence: u32,
+    flags: u32,
+    steal: u64,
+    preempted: u8,
+    pad: [u8; 47],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SHMEM_INIT: AtomicUsize = AtomicUsize::new(NO_SHMEM);
+static SHMEM: [AtomicUsize; NUM_HART_MAX] = [SHMEM_INIT; NUM_HART_MAX];
+
+/// Updates the steal-time record of given hart with `f`, if it has one.
+fn update(hart_id: usize, f: impl FnOnce(*mut StealTime)) {
+    let record =
+        match crate::hart::entry_of(&SHMEM, hart_id).map(|slot| slot.load(Ordering::Acquire)) {
+            Some(NO_SHMEM) | None => return,
+            Some(addr) => addr as *mut StealTime,
+        };
+    unsafe {
+        let sequence = addr_of_mut!((*record).sequence);
+        let value = ptr::read_volatile(sequence);
+        ptr::write_volatile(sequence, value.wrapping_add(1));
+        fence(Ordering::Release);
+        f(record);
+        fence(Ordering::Release);
+        ptr::write_volatile(sequence, value.wrapping_add(2));
+    }
+}
+
+/// Marks current hart as preempted, returning when it was if it has a steal-time record.
+pub fn preempt() -> Option<u64> {
+    let hart_id = mhartid::read();
+    let slot = crate::hart::entry_of(&SHMEM, hart_id)?;
+    if slot.load(Ordering::Acquire) == NO_SHMEM {
+        return None;
+    }
+    let since = crate::clint::mtime_ns()?;
+    update(hart_id, |record| unsafe {
+        ptr::write_volatile(addr_of_mut!((*record).preempted), 1)
+    });
+    Some(since)
+}
+
+/// Adds time since `preempt` to stolen time of current hart and clears its preempted flag.
+pub fn resume(since: u64) {
+    let now = crate::clint::mtime_ns().unwrap_or(since);
+    update(mhartid::read(), |record| unsafe {
+        let steal = addr_of_mut!((*record).steal);
+        let value = ptr::read_volatile(steal).wrapping_add(now.saturating_sub(since));
+        ptr::write_volatile(steal, value);
+        ptr::write_volatile(addr_of_mut!((*record).preempted), 0);
+    });
+}
+
+/// SBI STA extension reporting stolen time of machine-mode interrupt handling.
+#[cfg(feature = "sta")]
+pub struct MachineSta;
+
+#[cfg(feature = "sta")]
+impl rustsbi::Sta for MachineSta {
+    fn set_shmem(&self, shmem: SharedPtr<[u8; 64]>, flags: usize) -> SbiRet {
+        let hart_id = mhartid::read();
+        let Some(slot) = crate::hart::entry_of(&SHMEM, hart_id) else {
+            return SbiRet::failed();
+        };
+        let (lo, hi) = (shmem.phys_addr_lo(), shmem.phys_addr_hi());
+        if flags != 0 {
+            return SbiRet::invalid_param();
+        }
+        if lo == usize::MAX && hi == usize::MAX {
+            slot.store(NO_SHMEM, Ordering::Release);
+            return SbiRet::success(0);
+        }
+        if lo % RECORD_SIZE != 0 {
+            return SbiRet::invalid_param();
+        }
+        if hi != 0 || !crate::memory::is_supervisor_memory(lo, RECORD_SIZE) {
+            return SbiRet::invalid_address();
+        }
+        unsafe { ptr::write_bytes(lo as *mut u8, 0, RECORD_SIZE) };
+        slot.store(lo, Ordering::Release);
+        trace!("hart {} steal-time record at 0x{:x}", hart_id, lo);
+        SbiRet::success(0)
+    }
+}
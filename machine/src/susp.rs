@@ -0,0 +1,95 @@
+//! System suspend, entered by the last running hart once all other harts are stopped.
+//!
+//! Entering a low-power state is left to a board hook; machine-mode state the hook may
+//! lose is saved before and restored after, and other per-hart state is initialized again
+//! when the hart resumes supervisor software through the same path as `hart_start`.
+
+use crate::hsm::HartStart;
+use core::arch::asm;
+use riscv::register::{mhartid, mie, mip, mtvec};
+use rustsbi::SbiRet;
+
+/// `SUSPEND_TO_RAM` sleep type defined by SBI.
+const SUSPEND_TO_RAM: u32 = 0;
+
+/// Board specific entry into low-power states of system suspend.
+pub trait SleepHook {
+    /// Whether the board supports given sleep type.
+    fn supports(&self, sleep_type: u32) -> bool;
+
+    /// Enters the low-power state of a supported sleep type, returning once woken up.
+    fn sleep(&self, sleep_type: u32);
+}
+
+/// Suspend to RAM by waiting for an interrupt enabled in `mie`, e.g. on QEMU virt.
+pub struct WfiSleep;
+
+impl SleepHook for WfiSleep {
+    #[inline]
+    fn supports(&self, sleep_type: u32) -> bool {
+        sleep_type == SUSPEND_TO_RAM
+    }
+
+    fn sleep(&self, _sleep_type: u32) {
+        loop {
+            riscv::asm::wfi();
+            if mip::read().bits() & mie::read().bits() != 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Machine-mode registers saved across the low-power state.
+struct MachineState {
+    mie: usize,
+    mtvec: usize,
+}
+
+impl MachineState {
+    #[inline]
+    fn save() -> Self {
+        Self {
+            mie: mie::read().bits(),
+            mtvec: mtvec::read().bits(),
+        }
+    }
+
+    #[inline]
+    unsafe fn restore(&self) {
+        asm!("csrw mie, {}", in(reg) self.mie);
+        asm!("csrw mtvec, {}", in(reg) self.mtvec);
+    }
+}
+
+/// SBI SUSP extension calling a board hook to enter the low-power state.
+pub struct MachineSusp<H> {
+    pub hook: H,
+}
+
+impl<H: SleepHook> rustsbi::Susp for MachineSusp<H> {
+    fn system_suspend(&self, sleep_type: u32, resume_addr: usize, opaque: usize) -> SbiRet {
+        match sleep_type {
+            SUSPEND_TO_RAM | 0x8000_0000..=0xffff_ffff if self.hook.supports(sleep_type) => {}
+            SUSPEND_TO_RAM | 0x8000_0000..=0xffff_ffff => return SbiRet::not_supported(),
+            _ => return SbiRet::invalid_param(),
+        }
+        if !crate::memory::is_supervisor_memory(resume_addr, 4) {
+            return SbiRet::invalid_address();
+        }
+        let hart_id = mhartid::read();
+        if !crate::hsm::others_stopped(hart_id) {
+            return SbiRet::denied();
+        }
+        trace!("hart {} enters system suspend type {}", hart_id, sleep_type);
+        let state = MachineState::save();
+        self.hook.sleep(sleep_type);
+        unsafe { state.restore() };
+        trace!("hart {} resumes from system suspend", hart_id);
+        let start = HartStart {
+            start_addr: resume_addr,
+            opaque,
+        };
+        unsafe { crate::hsm::enter_supervisor(hart_id, start) }
+    }
+}
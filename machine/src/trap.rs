@@ -0,0 +1,207 @@
+//! Machine-mode trap entry and SBI call dispatch.
+
+use riscv::register::{
+    mcause::{self, Exception, Interrupt, Trap},
+    mhartid, mie, mip, mtval,
+};
+use rustsbi::{spec::pmu::firmware_event, SbiRet};
+
+/// Supervisor context saved on the machine-mode stack when a trap is taken.
+///
+/// General purpose registers are listed in order of `x1` to `x31`.
+#[repr(C)]
+pub struct SupervisorContext {
+    pub ra: usize,
+    pub sp: usize,
+    pub gp: usize,
+    pub tp: usize,
+    pub t0: usize,
+    pub t1: usize,
+    pub t2: usize,
+    pub s0: usize,
+    pub s1: usize,
+    pub a0: usize,
+    pub a1: usize,
+    pub a2: usize,
+    pub a3: usize,
+    pub a4: usize,
+    pub a5: usize,
+    pub a6: usize,
+    pub a7: usize,
+    pub s2: usize,
+    pub s3: usize,
+    pub s4: usize,
+    pub s5: usize,
+    pub s6: usize,
+    pub s7: usize,
+    pub s8: usize,
+    pub s9: usize,
+    pub s10: usize,
+    pub s11: usize,
+    pub t3: usize,
+    pub t4: usize,
+    pub t5: usize,
+    pub t6: usize,
+    /// Address the trapped hart resumes at after `mret`.
+    pub mepc: usize,
+}
+
+/// Machine-mode trap entry.
+///
+/// `mscratch` holds the top of the current hart's machine-mode stack while
+/// this hart runs in lower privilege modes; it is swapped with `sp` on entry.
+///
+/// `mtvec` base must be 4-byte aligned; the linker script aligns this function's section.
+#[naked]
+#[link_section = ".text.trap_entry"]
+pub unsafe extern "C" fn trap_entry() -> ! {
+    core::arch::asm!(
+        // 1. Switch to machine-mode stack and save supervisor context
+        "   csrrw   sp, mscratch, sp
+            addi    sp, sp, -{context_size}
+            sd      ra, 0*8(sp)
+            sd      gp, 2*8(sp)
+            sd      tp, 3*8(sp)
+            sd      t0, 4*8(sp)
+            sd      t1, 5*8(sp)
+            sd      t2, 6*8(sp)
+            sd      s0, 7*8(sp)
+            sd      s1, 8*8(sp)
+            sd      a0, 9*8(sp)
+            sd      a1, 10*8(sp)
+            sd      a2, 11*8(sp)
+            sd      a3, 12*8(sp)
+            sd      a4, 13*8(sp)
+            sd      a5, 14*8(sp)
+            sd      a6, 15*8(sp)
+            sd      a7, 16*8(sp)
+            sd      s2, 17*8(sp)
+            sd      s3, 18*8(sp)
+            sd      s4, 19*8(sp)
+            sd      s5, 20*8(sp)
+            sd      s6, 21*8(sp)
+            sd      s7, 22*8(sp)
+            sd      s8, 23*8(sp)
+            sd      s9, 24*8(sp)
+            sd      s10, 25*8(sp)
+            sd      s11, 26*8(sp)
+            sd      t3, 27*8(sp)
+            sd      t4, 28*8(sp)
+            sd      t5, 29*8(sp)
+            sd      t6, 30*8(sp)
+            csrr    t0, mscratch
+            sd      t0, 1*8(sp)
+            csrr    t0, mepc
+            sd      t0, 31*8(sp)",
+        // 2. Run Rust trap handler
+        "   mv      a0, sp
+            call    {handler}",
+        // 3. Restore supervisor context and return
+        "   ld      t0, 31*8(sp)
+            csrw    mepc, t0
+            ld      ra, 0*8(sp)
+            ld      gp, 2*8(sp)
+            ld      tp, 3*8(sp)
+            ld      t0, 4*8(sp)
+            ld      t1, 5*8(sp)
+            ld      t2, 6*8(sp)
+            ld      s0, 7*8(sp)
+            ld      s1, 8*8(sp)
+            ld      a0, 9*8(sp)
+            ld      a1, 10*8(sp)
+            ld      a2, 11*8(sp)
+            ld      a3, 12*8(sp)
+            ld      a4, 13*8(sp)
+            ld      a5, 14*8(sp)
+            ld      a6, 15*8(sp)
+            ld      a7, 16*8(sp)
+            ld      s2, 17*8(sp)
+            ld      s3, 18*8(sp)
+            ld      s4, 19*8(sp)
+            ld      s5, 20*8(sp)
+            ld      s6, 21*8(sp)
+            ld      s7, 22*8(sp)
+            ld      s8, 23*8(sp)
+            ld      s9, 24*8(sp)
+            ld      s10, 25*8(sp)
+            ld      s11, 26*8(sp)
+            ld      t3, 27*8(sp)
+            ld      t4, 28*8(sp)
+            ld      t5, 29*8(sp)
+            ld      t6, 30*8(sp)
+            addi    sp, sp, {context_size}
+            csrrw   sp, mscratch, sp
+            mret",
+        context_size = const core::mem::size_of::<SupervisorContext>(),
+        handler = sym machine_trap_handler,
+        options(noreturn)
+    )
+}
+
+extern "C" fn machine_trap_handler(ctx: &mut SupervisorContext) {
+    let cause = mcause::read().cause();
+    // interrupts handled here are time stolen from supervisor software
+    let preempted_since = match cause {
+        Trap::Interrupt(_) => crate::sta::preempt(),
+        Trap::Exception(_) => None,
+    };
+    match cause {
+        Trap::Exception(Exception::SupervisorEnvCall) => {
+            let param = [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5];
+            #[cfg(feature = "legacy")]
+            if let Some(a0) = crate::legacy::handle_legacy(ctx.a7, param) {
+                ctx.a0 = a0;
+                ctx.mepc = ctx.mepc.wrapping_add(4);
+                return;
+            }
+            let ans = handle_ecall(ctx.a7, ctx.a6, param);
+            ctx.a0 = ans.error;
+            ctx.a1 = ans.value;
+            ctx.mepc = ctx.mepc.wrapping_add(4);
+        }
+        Trap::Interrupt(Interrupt::MachineSoft) => {
+            let reasons = crate::clint::take_ipi(mhartid::read());
+            if reasons & crate::clint::IPI_SUPERVISOR != 0 {
+                crate::pmu::record(firmware_event::IPI_RECEIVED);
+                unsafe { mip::set_ssoft() };
+            }
+            if reasons & crate::clint::IPI_FENCE != 0 {
+                crate::rfence::handle_pending(mhartid::read());
+            }
+        }
+        Trap::Interrupt(Interrupt::MachineTimer) => unsafe {
+            // `mtip` stays pending until supervisor sets the next timer,
+            // so mask it here and forward the interrupt as `stip`
+            mie::clear_mtimer();
+            mip::set_stimer();
+        },
+        cause => {
+            error!(
+                "unhandled machine trap {:?}, mepc = 0x{:x}, mtval = 0x{:x}",
+                cause,
+                ctx.mepc,
+                mtval::read()
+            );
+            crate::reset::fail()
+        }
+    }
+    if let Some(since) = preempted_since {
+        crate::sta::resume(since);
+    }
+}
+
+#[inline]
+pub fn handle_ecall(extension: usize, function: usize, param: [usize; 6]) -> SbiRet {
+    match () {
+        #[cfg(feature = "fdt")]
+        () => match crate::fdt::BOARD.get() {
+            Some(board) => rustsbi::RustSBI::handle_ecall(board, extension, function, param),
+            None => SbiRet::not_supported(),
+        },
+        #[cfg(not(feature = "fdt"))]
+        () => {
+            let _ = (extension, function, param);
+            SbiRet::not_supported()
+        }
+    }
+}
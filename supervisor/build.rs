@@ -0,0 +1,39 @@
+use std::{env, path::PathBuf};
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let ld = &out.join("rustsbi-supervisor.ld");
+
+    std::fs::write(ld, LINKER_SCRIPT).unwrap();
+
+    println!("cargo:rustc-link-arg=-T{}", ld.display());
+    println!("cargo:rustc-link-search={}", out.display());
+}
+
+const LINKER_SCRIPT: &[u8] = b"OUTPUT_ARCH(riscv)
+ENTRY(_start)
+SECTIONS {
+    . = 0x80200000;
+    .text : ALIGN(8) {
+        *(.text.entry)
+        *(.text .text.*)
+    }
+    .rodata : ALIGN(8) {
+        *(.rodata .rodata.*)
+        *(.srodata .srodata.*)
+    }
+    .data : ALIGN(8) {
+        *(.data .data.*)
+        *(.sdata .sdata.*)
+    }
+    .bss (NOLOAD) : ALIGN(8) {
+        *(.bss.uninit)
+        sbss = .;
+        *(.bss .bss.*)
+        *(.sbss .sbss.*)
+        ebss = .;
+    }
+    /DISCARD/ : {
+        *(.eh_frame)
+    }
+}";
@@ -1,3 +1,75 @@
-fn main() {
-    println!("Hello, world!");
+#![feature(naked_functions, asm_const)]
+#![no_std]
+#![no_main]
+
+const LEN_STACK: usize = 16 * 1024;
+
+extern "C" fn main(hart_id: usize, _opaque: usize) -> ! {
+    print("Hello from RustSBI supervisor payload on hart ");
+    print_usize(hart_id);
+    print("\r\n");
+    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
+    loop {
+        core::hint::spin_loop()
+    }
+}
+
+fn print(s: &str) {
+    for byte in s.bytes() {
+        sbi_rt::console_write_byte(byte);
+    }
+}
+
+fn print_usize(mut value: usize) {
+    let mut buf = [0u8; 20];
+    let mut idx = buf.len();
+    loop {
+        idx -= 1;
+        buf[idx] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    for &byte in &buf[idx..] {
+        sbi_rt::console_write_byte(byte);
+    }
+}
+
+#[repr(C, align(128))]
+struct Stack<const N: usize>([u8; N]);
+
+#[link_section = ".bss.uninit"]
+static STACK: Stack<LEN_STACK> = Stack([0; LEN_STACK]);
+
+#[naked]
+#[link_section = ".text.entry"]
+#[export_name = "_start"]
+unsafe extern "C" fn entry() -> ! {
+    core::arch::asm!(
+        // 1. Prepare stack; only one hart is expected to enter here
+        "   la      sp, {stack} + {stack_size}",
+        // 2. Clear bss segment, keeping a0 (hart id) and a1 (opaque)
+        "   la      t0, sbss
+            la      t1, ebss
+        1:  bgeu    t0, t1, 2f
+            sd      zero, 0(t0)
+            addi    t0, t0, 8
+            j       1b",
+        "2: ",
+        // 3. Run Rust main function
+        "   j       {main}",
+        stack_size = const LEN_STACK,
+        stack = sym STACK,
+        main = sym main,
+        options(noreturn)
+    )
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
+    loop {
+        core::hint::spin_loop()
+    }
 }
@@ -15,6 +15,7 @@ pub struct App {
     pub standard_sbi_enabled: StandardSbiEnabled,
     pub machine_mode_fdt_ident_enabled: bool,
     pub machine_mode_dynamicinfo_ident_enabled: bool,
+    pub machine_mode_legacy_sbi_enabled: bool,
     pub platform: Platform,
     pub supervisor_mode_brief: &'static str,
     pub bootload_media_brief: &'static str,
@@ -165,6 +166,14 @@ impl App {
         locale::get_string(idx, &self.locale)
     }
 
+    pub fn legacy_sbi_brief(&self) -> &'static str {
+        let idx = match self.machine_mode_legacy_sbi_enabled {
+            true => "enabled",
+            false => "disabled",
+        };
+        locale::get_string(idx, &self.locale)
+    }
+
     pub fn platform_support_brief(&self) -> &'static str {
         let idx = match self.platform {
             Platform::AllwinnerD1Series => "platform-support.allwinner-d1-series",
@@ -185,6 +194,10 @@ impl From<crate::Config> for App {
             standard_sbi_enabled: value.standard_sbi_enabled.unwrap_or_default(),
             platform: value.platform,
             machine_mode_fdt_ident_enabled: value.machine_fdt_ident_enabled.unwrap_or(true),
+            machine_mode_dynamicinfo_ident_enabled: value
+                .machine_dynamicinfo_ident_enabled
+                .unwrap_or(true),
+            machine_mode_legacy_sbi_enabled: value.machine_legacy_sbi_enabled.unwrap_or(false),
             ..Default::default()
         }
     }
@@ -207,6 +220,7 @@ impl Default for App {
             help_ver_about_brief: "",
             machine_mode_fdt_ident_enabled: true,
             machine_mode_dynamicinfo_ident_enabled: true,
+            machine_mode_legacy_sbi_enabled: false,
         }
     }
 }
@@ -227,6 +241,7 @@ pub enum RouteId {
     StandardSbiFeat,
     FdtIdent,
     DynamicInfoIdent,
+    LegacySbi,
     // route for each platform:
     AllwinnerD1Series,
     Sophgo2002Series,
@@ -328,6 +343,14 @@ impl Default for StandardSbiEnabled {
     }
 }
 
+/// RISC-V privilege mode of the boot-loading stage after machine mode environment.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum NextMode {
+    Machine,
+    Supervisor,
+    User,
+}
+
 pub trait IsSupported {
     fn is_bootstrap_supported(&self, bootstrap: &Bootstrap) -> bool;
 }
@@ -1,27 +1,73 @@
-use crate::Config;
+use crate::{
+    app::{NextMode, StandardSbiEnabled},
+    Config,
+};
 use os_xtask_utils::{BinUtil, Cargo, CommandExt};
+use std::path::{Path, PathBuf};
 
 const TARGET: &'static str = "riscv64imac-unknown-none-elf";
 
 pub fn build_no_specific_platform(config: &Config) {
     let features = machine_features_from_config(config);
+    let payload = features.contains(&"payload").then(|| build_payload(config));
     Cargo::build()
         .package("rustsbi-machine")
         .features(false, features)
         .target(TARGET)
         .release()
+        .optional(&config.machine_jump_address, |cargo, address| {
+            cargo.env("RUSTSBI_JUMP_ADDRESS", format!("0x{:x}", address));
+        })
+        .optional(&config.machine_jump_mode, |cargo, mode| {
+            cargo.env("RUSTSBI_JUMP_MODE", jump_mode_env(mode));
+        })
+        .optional(&payload, |cargo, path| {
+            cargo.env("RUSTSBI_PAYLOAD", path);
+        })
         .invoke();
     let elf_path = crate::PROJECT
         .join("target")
         .join(TARGET)
         .join("release")
         .join("rustsbi-machine");
-    let bin_path = elf_path.with_extension("bin");
+    objcopy_binary(&elf_path, &elf_path.with_extension("bin"));
+}
+
+/// Prepares payload as a raw binary image, returning its absolute path.
+///
+/// Uses the image given by `machine-payload-path` if any, or builds `rustsbi-supervisor` otherwise.
+fn build_payload(config: &Config) -> PathBuf {
+    let release_dir = crate::PROJECT.join("target").join(TARGET).join("release");
+    let elf_path = match &config.machine_payload_path {
+        // raw binary images are embedded as is
+        Some(path) if !is_elf(&crate::PROJECT.join(path)) => return crate::PROJECT.join(path),
+        Some(path) => crate::PROJECT.join(path),
+        None => {
+            Cargo::build()
+                .package("rustsbi-supervisor")
+                .target(TARGET)
+                .release()
+                .invoke();
+            release_dir.join("rustsbi-supervisor")
+        }
+    };
+    let bin_path = release_dir.join("rustsbi-payload.bin");
+    objcopy_binary(&elf_path, &bin_path);
+    bin_path
+}
+
+fn is_elf(path: &Path) -> bool {
+    let image =
+        std::fs::read(path).unwrap_or_else(|e| panic!("read payload '{}': {}", path.display(), e));
+    image.starts_with(b"\x7fELF")
+}
+
+fn objcopy_binary(elf_path: &Path, bin_path: &Path) {
     BinUtil::objcopy()
         .arg("--binary-architecture=riscv64")
         .arg(elf_path)
         .args(["--strip-all", "-O", "binary"])
-        .arg(&bin_path)
+        .arg(bin_path)
         .invoke();
 }
 
@@ -30,5 +76,47 @@ fn machine_features_from_config(config: &Config) -> Vec<&'static str> {
     if config.machine_fdt_ident_enabled.unwrap_or(true) {
         ans.push("fdt");
     }
+    // an embedded payload overrides a fixed jump address, which overrides dynamic information
+    if config.machine_payload_enabled.unwrap_or(false) {
+        ans.push("payload");
+    } else if config.machine_jump_address.is_some() {
+        ans.push("jump");
+    } else if config.machine_dynamicinfo_ident_enabled.unwrap_or(true) {
+        ans.push("dynamic");
+    }
+    if config.machine_legacy_sbi_enabled.unwrap_or(false) {
+        ans.push("legacy");
+    }
+    // extensions without a toggle in the config are enabled
+    let enabled = |f: fn(&StandardSbiEnabled) -> bool| {
+        config.standard_sbi_enabled.as_ref().map(f).unwrap_or(true)
+    };
+    if enabled(|e| e.hsm) {
+        ans.push("hsm");
+    }
+    if enabled(|e| e.pmu) {
+        ans.push("pmu");
+    }
+    if enabled(|e| e.susp) {
+        ans.push("susp");
+    }
+    if enabled(|e| e.nacl) {
+        ans.push("nacl");
+    }
+    if enabled(|e| e.sta) {
+        ans.push("sta");
+    }
+    // QEMU has no performance control, so CPPC is provided by simulated registers
+    if enabled(|e| e.cppc) {
+        ans.push("simulated-cppc");
+    }
     ans
 }
+
+fn jump_mode_env(mode: &NextMode) -> &'static str {
+    match mode {
+        NextMode::Machine => "M",
+        NextMode::Supervisor => "S",
+        NextMode::User => "U",
+    }
+}
@@ -41,6 +41,9 @@ static LOCALE: Lazy<HashMap<&'static str, HashMap<&'static str, &'static str>>>
     ("machine-mode.dynamic-info-ident", [("zh-CN", "动态信息识别"), ("en-US", "Dynamic Information identification")].into()),
     ("dynamic-info-ident.dynamic-info-ident-enabled", [("zh-CN", "启动动态信息识别"), ("en-US", "Dynamic Information identification enabled")].into()),
     ("dynamic-info-ident.dynamic-info-ident-enabled.enabled", [("zh-CN", "启动动态信息识别"), ("en-US", "Dynamic Information identification enabled")].into()),
+    ("machine-mode.legacy-sbi", [("zh-CN", "旧版 SBI 调用"), ("en-US", "Legacy SBI calls")].into()),
+    ("legacy-sbi.title", [("zh-CN", " RustSBI 原型设计系统 - 旧版 SBI 调用 "), ("en-US", " RustSBI Prototyping System - Legacy SBI calls ")].into()),
+    ("legacy-sbi.legacy-sbi-enabled.enabled", [("zh-CN", "启用 SBI v0.1 旧版调用"), ("en-US", "SBI v0.1 legacy calls enabled")].into()),
     ("machine-mode.not-supported", [("zh-CN", "启动程序不支持机器态功能"), ("en-US", "Bootstrap program does not support machine mode features")].into()),
     ("platform-support.title", [("zh-CN", " RustSBI 原型设计系统 - 平台支持 "), ("en-US", " RustSBI Prototyping System - Platform support ")].into()),
     ("platform-support.allwinner-d1-series", [("zh-CN", "全志® D1-H 系列平台"), ("en-US", "Allwinner® D1-H series")].into()),
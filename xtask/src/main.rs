@@ -6,12 +6,16 @@ mod tool;
 mod ui;
 use crate::app::{App, RouteId};
 
-use app::{Bootstrap, Platform, StandardSbiEnabled};
+use app::{Bootstrap, NextMode, Platform, StandardSbiEnabled};
 use clap::{Args, Parser, Subcommand};
 use clap_verbosity_flag::Verbosity;
 use log::error;
 use once_cell::sync::Lazy;
-use std::{error::Error, fs, io, path::Path};
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
 use toml_edit::{value, DocumentMut};
 
 #[derive(Parser)]
@@ -35,7 +39,44 @@ enum Commands {
 }
 
 #[derive(Args)]
-struct BuildArgs {}
+struct BuildArgs {
+    /// Jump to this address after machine mode environment is initialized
+    #[clap(long, value_parser = parse_address)]
+    jump: Option<u64>,
+    /// Embed supervisor payload into machine mode binary
+    #[clap(long)]
+    payload: bool,
+    /// Payload image (ELF or raw binary) to embed instead of `rustsbi-supervisor`
+    #[clap(long)]
+    payload_path: Option<PathBuf>,
+    /// Support legacy SBI v0.1 calls
+    #[clap(long)]
+    legacy: bool,
+}
+
+impl BuildArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(jump) = self.jump {
+            config.machine_jump_address = Some(jump);
+        }
+        if self.payload || self.payload_path.is_some() {
+            config.machine_payload_enabled = Some(true);
+        }
+        if let Some(path) = &self.payload_path {
+            config.machine_payload_path = Some(path.clone());
+        }
+        if self.legacy {
+            config.machine_legacy_sbi_enabled = Some(true);
+        }
+    }
+}
+
+fn parse_address(s: &str) -> Result<u64, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
@@ -57,7 +98,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             write_config_file(&buf)?;
             Ok(())
         }
-        Commands::Make(_args) => {
+        Commands::Make(args) => {
             if !config_file_exists()? {
                 error!("No configuration files given and Xtask.toml does not exist!");
                 error!("Configure RustSBI Prototyping System using:");
@@ -65,11 +106,12 @@ fn main() -> Result<(), Box<dyn Error>> {
                 return Err(Box::new(io::Error::from(io::ErrorKind::NotFound)));
             }
             buf = read_config_file()?;
-            let config: Config = toml_edit::de::from_str(&buf)?;
+            let mut config: Config = toml_edit::de::from_str(&buf)?;
+            args.apply(&mut config);
             build::build_main(&config)?;
             Ok(())
         }
-        Commands::Flash(_args) => {
+        Commands::Flash(args) => {
             if !config_file_exists()? {
                 error!("No configuration files given and Xtask.toml does not exist!");
                 error!("Configure RustSBI Prototyping System using:");
@@ -77,7 +119,8 @@ fn main() -> Result<(), Box<dyn Error>> {
                 return Err(Box::new(io::Error::from(io::ErrorKind::NotFound)));
             }
             buf = read_config_file()?;
-            let config: Config = toml_edit::de::from_str(&buf)?;
+            let mut config: Config = toml_edit::de::from_str(&buf)?;
+            args.apply(&mut config);
             build::build_main(&config)?;
             build::flash_main(&config)?;
             Ok(())
@@ -95,6 +138,12 @@ pub struct Config {
     bootstrap: Bootstrap,
     standard_sbi_enabled: Option<StandardSbiEnabled>,
     machine_fdt_ident_enabled: Option<bool>,
+    machine_dynamicinfo_ident_enabled: Option<bool>,
+    machine_jump_address: Option<u64>,
+    machine_jump_mode: Option<NextMode>,
+    machine_payload_enabled: Option<bool>,
+    machine_payload_path: Option<PathBuf>,
+    machine_legacy_sbi_enabled: Option<bool>,
     platform: Platform,
 }
 
@@ -128,6 +177,8 @@ fn save_app_to_string(app: &App, buf: &mut String) -> io::Result<()> {
     doc["standard-sbi-enabled"]["nacl"] = value(nacl);
     doc["standard-sbi-enabled"]["sta"] = value(sta);
     doc["machine-fdt-ident-enabled"] = value(app.machine_mode_fdt_ident_enabled);
+    doc["machine-dynamicinfo-ident-enabled"] = value(app.machine_mode_dynamicinfo_ident_enabled);
+    doc["machine-legacy-sbi-enabled"] = value(app.machine_mode_legacy_sbi_enabled);
     doc["platform"] = value(to_variant_name(&app.platform).unwrap());
     *buf = doc.to_string();
     Ok(())
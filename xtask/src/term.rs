@@ -62,6 +62,7 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> io::Result<
             RouteId::StandardSbiFeat => ui::draw_standard_sbi_features(f, app),
             RouteId::FdtIdent => ui::draw_fdt_ident(f, app),
             RouteId::DynamicInfoIdent => ui::draw_dynamicinfo_ident(f, app),
+            RouteId::LegacySbi => ui::draw_legacy_sbi(f, app),
             _ => todo!(),
         })?;
 
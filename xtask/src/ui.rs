@@ -21,6 +21,8 @@ mod fdt_ident;
 pub use fdt_ident::draw_fdt_ident;
 mod dynamicinfo_ident;
 pub use dynamicinfo_ident::draw_dynamicinfo_ident;
+mod legacy_sbi;
+pub use legacy_sbi::draw_legacy_sbi;
 
 use crate::{
     app::App,
@@ -0,0 +1,34 @@
+use crate::{ui::Builder, App};
+use ratatui::{layout::Constraint::*, Frame};
+use std::ops::ControlFlow;
+
+pub fn draw_legacy_sbi(f: &mut Frame, app: &mut App) {
+    fn choose_str(enabled: bool) -> &'static str {
+        match enabled {
+            true => "enabled",
+            false => "disabled",
+        }
+    }
+    #[rustfmt::skip]
+    let items = vec![
+        vec!["LegacySbiEnabled", "legacy-sbi.legacy-sbi-enabled.enabled", choose_str(app.machine_mode_legacy_sbi_enabled)],
+        vec!["Back", "back", ""],
+    ];
+    fn machine_mode_handle(idx: usize, app: &mut App) -> ControlFlow<(), ()> {
+        match idx {
+            0 => app.machine_mode_legacy_sbi_enabled = !app.machine_mode_legacy_sbi_enabled,
+            1 => return ControlFlow::Break(()),
+            _ => unreachable!(),
+        };
+        ControlFlow::Continue(())
+    }
+    Builder {
+        title: "legacy-sbi.title",
+        header: vec!["id", "home.item", "home.brief"],
+        items,
+        item_translate_idx: vec![1, 2],
+        widths: vec![Length(18), Min(30), Length(12)],
+        control_flow_fn: machine_mode_handle,
+    }
+    .draw(f, app)
+}
@@ -8,6 +8,7 @@ pub fn draw_machine_mode(f: &mut Frame, app: &mut App) {
         vec!["StandardSbiFeat", "machine-mode.standard-sbi-feat", app.standard_sbi_brief(), ">"],
         vec!["FdtIdent", "machine-mode.fdt-ident", app.fdtident_brief(), ">"],
         vec!["DynamicInfoIdent", "machine-mode.dynamic-info-ident", app.dynamicinfoident_brief(), ">"],
+        vec!["LegacySbi", "machine-mode.legacy-sbi", app.legacy_sbi_brief(), ">"],
         vec!["Back", "back", "", ""],
     ];
     fn machine_mode_handle(idx: usize, app: &mut App) -> ControlFlow<(), ()> {
@@ -15,7 +16,8 @@ pub fn draw_machine_mode(f: &mut Frame, app: &mut App) {
             0 => app.push_route(RouteId::StandardSbiFeat),
             1 => app.push_route(RouteId::FdtIdent),
             2 => app.push_route(RouteId::DynamicInfoIdent),
-            3 => return ControlFlow::Break(()),
+            3 => app.push_route(RouteId::LegacySbi),
+            4 => return ControlFlow::Break(()),
             _ => unreachable!(),
         };
         ControlFlow::Continue(())